@@ -5,6 +5,7 @@ fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=Cargo.lock");
     println!("cargo:rerun-if-changed=config");
+    println!("cargo:rustc-env=RSS_HOST_TARGET={}", env::var("TARGET").unwrap());
     let binding = PathBuf::from(env::var_os("OUT_DIR").unwrap());
     let target_dir = binding
         .parent()
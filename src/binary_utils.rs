@@ -1,50 +1,325 @@
-use std::ffi::OsStr;
-use std::fs;
+use std::env::consts::{ARCH, OS};
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
 use std::path::Path;
-use std::process::Command;
-#[cfg(target_os = "windows")]
-use crate::os_str_utils::Append;
+use std::process::{self, Command, ExitStatus};
+#[cfg(target_os = "linux")]
+use std::os::unix::process::ExitStatusExt;
+use crate::signal_utils::{run_forwarding_signals, Forward};
+#[cfg(target_os = "linux")]
+use crate::signal_utils::exit_with_signal;
 
-pub fn write_binary(rss_file: &Path, binary: &[u8]) -> Result<(), String> {
-    let file_name = rss_file.file_stem().unwrap();
-    let directory = rss_file.parent().unwrap();
+/// Runs the binary with `args`, inheriting stdin, stdout and stderr, and returns its exit status
+pub fn execute_binary(exe_file: &Path, args: &[OsString]) -> Result<ExitStatus, String> {
+    run_forwarding_signals(Command::new(exe_file).args(args), Forward::Process).map_err(|_| format!("Failed to execute binary {}", exe_file.display()))
+}
 
-    #[cfg(target_os = "windows")]
-    let exe_file = directory.join(file_name.to_os_string().append(OsStr::new(".exe")));
+/// How embedded binaries are executed
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionStrategy {
+    /// Extracted to the user cache and run from there
+    Cache,
+    /// Loaded into an anonymous memory file and run without writing to disk. Only supported on Linux.
+    Memory
+}
 
-    #[cfg(target_os = "linux")]
-    let exe_file = directory.join(file_name);
+impl ExecutionStrategy {
+    pub fn from_str(s: &str) -> Result<ExecutionStrategy, String> {
+        Ok(match s {
+            "cache" => ExecutionStrategy::Cache,
+            "memory" => ExecutionStrategy::Memory,
+            s => return Err(format!("Execution strategy [{s}] in config file not supported"))
+        })
+    }
+}
+
+/// Runs the binary from an anonymous `memfd_create` file with `fexecve`, with `argv[0]` set to the rss file,
+/// inheriting stdin, stdout and stderr, and returns its exit status.
+/// Fails without running anything if memory files can't be created or executed, so the caller can fall back to the cache.
+#[cfg(target_os = "linux")]
+pub fn execute_binary_in_memory(rss_file: &Path, binary: &[u8], args: &[OsString]) -> Result<ExitStatus, String> {
+    use std::ffi::{CString, OsStr};
+    use std::fs::File;
+    use std::io::{self, Write};
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+    use std::{env, iter, ptr};
 
-    fs::write(&exe_file, binary).map_err(|_| format!("Failed to write binary to {}", exe_file.display()))?;
+    let fd = unsafe { libc::memfd_create(c"rss".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err("Failed to create memory file".to_string());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(binary).map_err(|_| "Failed to write binary to memory file".to_string())?;
 
+    // Built before forking as the child may only make async-signal-safe calls
+    let to_c_strings = |strings: Vec<Vec<u8>>| strings.into_iter().map(CString::new).collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "Arguments and environment variables cannot contain nul bytes".to_string());
+    let argv = to_c_strings(iter::once(rss_file.as_os_str()).chain(args.iter().map(OsString::as_os_str))
+        .map(|a| a.as_bytes().to_vec()).collect())?;
+    let envp = to_c_strings(env::vars_os()
+        .map(|(k, v)| [k.as_bytes(), b"=", v.as_bytes()].concat()).collect())?;
+    let null_terminated = |strings: &[CString]| strings.iter().map(|s| s.as_ptr()).chain(iter::once(ptr::null())).collect::<Vec<_>>();
+    let (argv_ptrs, envp_ptrs) = (null_terminated(&argv), null_terminated(&envp));
+
+    // Raw pointers are not `Send` so are passed into the child as addresses
+    let (fd, argv_ptr, envp_ptr) = (file.as_raw_fd(), argv_ptrs.as_ptr() as usize, envp_ptrs.as_ptr() as usize);
+    let mut command = Command::new(OsStr::new(rss_file));
+    unsafe {
+        command.pre_exec(move || {
+            libc::fexecve(fd, argv_ptr as *const *const libc::c_char, envp_ptr as *const *const libc::c_char);
+            Err(io::Error::last_os_error())
+        });
+    }
+    run_forwarding_signals(&mut command, Forward::Process).map_err(|_| "Failed to execute binary from memory".to_string())
+}
+
+/// Exits with the same status as the finished binary.
+/// If the binary was terminated by a signal, the same signal is raised against this process.
+pub fn exit_with_status(status: ExitStatus) -> ! {
     #[cfg(target_os = "linux")]
-    Command::new("chmod").args([OsStr::new("+x"), exe_file.as_os_str()]).status().map_err(|_| format!("Failed to mark binary as executable {}", exe_file.display()))?;
+    if let Some(signal) = status.signal() {
+        exit_with_signal(signal);
+    }
 
-    Ok(())
+    process::exit(status.code().unwrap_or(-1))
 }
 
-pub fn execute_binary(rss_file: &Path) -> Result<(), String> {
-    let file_name = rss_file.file_stem().unwrap();
-    let directory = rss_file.parent().unwrap();
-    #[cfg(target_os = "windows")]
-    let exe_file = directory.join(file_name.to_os_string().append(OsStr::new(".exe")));
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExecutableKind {
+    Elf,
+    Pe,
+    MachO
+}
 
-    #[cfg(target_os = "linux")]
-    let exe_file = directory.join(file_name);
+impl ExecutableKind {
+    /// The executable format used by an operating system, using the names from `std::env::consts::OS`
+    fn for_os(os: &str) -> Option<ExecutableKind> {
+        match os {
+            "windows" => Some(ExecutableKind::Pe),
+            "macos" | "ios" => Some(ExecutableKind::MachO),
+            "linux" | "android" | "freebsd" | "netbsd" | "openbsd" | "dragonfly" | "solaris" | "illumos" => Some(ExecutableKind::Elf),
+            _ => None
+        }
+    }
+}
 
-    Command::new(&exe_file).status().map_err(|_| format!("Failed to execute binary {}", exe_file.display()))?;
-    Ok(())
+/// The format and architecture of an executable, read from its header
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ExecutableFormat {
+    kind: ExecutableKind,
+    /// 32 or 64, if the header records it
+    bits: Option<u8>,
+    /// Architecture using the names from `std::env::consts::ARCH`, or `None` if not recognised
+    arch: Option<&'static str>
 }
 
-pub fn delete_binary(rss_file: &Path) -> Result<(), String> {
-    let file_name = rss_file.file_stem().unwrap();
-    let directory = rss_file.parent().unwrap();
-    #[cfg(target_os = "windows")]
-    let exe_file = directory.join(file_name.to_os_string().append(OsStr::new(".exe")));
+impl ExecutableFormat {
+    /// Reads the magic bytes of an executable. Returns `None` if the format is not recognised.
+    pub fn detect(binary: &[u8]) -> Option<ExecutableFormat> {
+        let u16_le = |i: usize| Some(u16::from_le_bytes(binary.get(i..i + 2)?.try_into().unwrap()));
+        let u32_le = |i: usize| Some(u32::from_le_bytes(binary.get(i..i + 4)?.try_into().unwrap()));
 
-    #[cfg(target_os = "linux")]
-    let exe_file = directory.join(file_name);
+        if binary.starts_with(b"\x7fELF") {
+            let bits = match binary.get(4)? { 1 => Some(32), 2 => Some(64), _ => None };
+            let machine = match binary.get(5)? {
+                2 => u16::from_be_bytes(binary.get(18..20)?.try_into().unwrap()),
+                _ => u16_le(18)?
+            };
+            let arch = match (machine, bits) {
+                (0x03, _) => Some("x86"),
+                (0x3E, _) => Some("x86_64"),
+                (0x28, _) => Some("arm"),
+                (0xB7, _) => Some("aarch64"),
+                (0xF3, Some(32)) => Some("riscv32"),
+                (0xF3, _) => Some("riscv64"),
+                (0x08, Some(32)) => Some("mips"),
+                (0x08, _) => Some("mips64"),
+                (0x14, _) => Some("powerpc"),
+                (0x15, _) => Some("powerpc64"),
+                (0x16, _) => Some("s390x"),
+                (0x102, _) => Some("loongarch64"),
+                _ => None
+            };
+            return Some(ExecutableFormat { kind: ExecutableKind::Elf, bits, arch });
+        }
+
+        if binary.starts_with(b"MZ") {
+            let pe_header = u32_le(0x3C)? as usize;
+            if binary.get(pe_header..pe_header + 4)? != b"PE\0\0" {
+                return None;
+            }
+            let (arch, bits) = match u16_le(pe_header + 4)? {
+                0x014C => (Some("x86"), Some(32)),
+                0x8664 => (Some("x86_64"), Some(64)),
+                0x01C4 => (Some("arm"), Some(32)),
+                0xAA64 => (Some("aarch64"), Some(64)),
+                _ => (None, None)
+            };
+            return Some(ExecutableFormat { kind: ExecutableKind::Pe, bits, arch });
+        }
+
+        let cpu_type = match u32_le(0)? {
+            0xFEEDFACE | 0xFEEDFACF => u32_le(4)?,
+            0xCEFAEDFE | 0xCFFAEDFE => u32::from_be_bytes(binary.get(4..8)?.try_into().unwrap()),
+            _ => return None
+        };
+        let (arch, bits) = match cpu_type {
+            0x0000_0007 => (Some("x86"), Some(32)),
+            0x0100_0007 => (Some("x86_64"), Some(64)),
+            0x0000_000C => (Some("arm"), Some(32)),
+            0x0100_000C => (Some("aarch64"), Some(64)),
+            _ => (None, None)
+        };
+        Some(ExecutableFormat { kind: ExecutableKind::MachO, bits, arch })
+    }
+
+    /// Whether this is the format of executables built for the `target` triple
+    pub fn is_for_target(&self, target: &str) -> bool {
+        let kind = if target.contains("-windows") {
+            ExecutableKind::Pe
+        } else if target.contains("-apple-") {
+            ExecutableKind::MachO
+        } else {
+            ExecutableKind::Elf
+        };
+        // Triples name architecture variants, `std::env::consts::ARCH` doesn't
+        let arch = match target.split('-').next().unwrap_or_default() {
+            "i386" | "i586" | "i686" => "x86",
+            a if a.starts_with("arm") || a.starts_with("thumb") => "arm",
+            a if a.starts_with("riscv64") => "riscv64",
+            a if a.starts_with("riscv32") => "riscv32",
+            a if a.starts_with("mips64") => "mips64",
+            a if a.starts_with("mips") => "mips",
+            a if a.starts_with("powerpc64") => "powerpc64",
+            a => a
+        };
+        self.kind == kind && self.arch == Some(arch)
+    }
+
+    /// Whether this executable can run on the current machine
+    pub fn runs_on_host(&self) -> bool {
+        self.runs_on(OS, ARCH)
+    }
+
+    /// Whether this executable can run on a machine with the `os` and `arch` named as in `std::env::consts`
+    fn runs_on(&self, os: &str, arch: &str) -> bool {
+        // x86_64 Linux runs 32-bit x86 executables too
+        let compatible = self.arch == Some(arch) || (arch == "x86_64" && self.arch == Some("x86") && self.kind == ExecutableKind::Elf);
+        ExecutableKind::for_os(os) == Some(self.kind) && compatible
+    }
+}
+
+impl Display for ExecutableFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.kind {
+            ExecutableKind::Elf => "ELF",
+            ExecutableKind::Pe => "Windows PE",
+            ExecutableKind::MachO => "Mach-O"
+        })?;
+        if let Some(bits) = self.bits {
+            write!(f, " {bits}-bit")?;
+        }
+        write!(f, " {}", self.arch.unwrap_or("unknown architecture"))
+    }
+}
+
+/// How much of the start of an executable is enough to detect its format
+pub const EXECUTABLE_HEADER_LENGTH: usize = 4096;
+
+/// Checks that a binary can be executed on this machine, explaining why not if it cannot
+pub fn check_executable_format(binary: &[u8]) -> Result<(), String> {
+    match ExecutableFormat::detect(binary) {
+        Some(format) if format.runs_on_host() => Ok(()),
+        Some(format) => Err(format!("Embedded binary is a {format} executable which cannot run on this {OS} {ARCH} machine")),
+        None => Err(format!("Embedded binary is not a recognised executable format for this {OS} {ARCH} machine"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of an ELF header for `machine`, with the class and byte order given
+    fn elf(bits: u8, big_endian: bool, machine: u16) -> Vec<u8> {
+        let mut header = vec![0; 64];
+        header[..4].copy_from_slice(b"\x7fELF");
+        header[4] = if bits == 32 { 1 } else { 2 };
+        header[5] = if big_endian { 2 } else { 1 };
+        let machine = if big_endian { machine.to_be_bytes() } else { machine.to_le_bytes() };
+        header[18..20].copy_from_slice(&machine);
+        header
+    }
+
+    /// A DOS stub pointing to a PE header for `machine` at `offset`
+    fn pe(offset: usize, machine: u16) -> Vec<u8> {
+        let mut header = vec![0; offset + 24];
+        header[..2].copy_from_slice(b"MZ");
+        header[0x3C..0x40].copy_from_slice(&(offset as u32).to_le_bytes());
+        header[offset..offset + 4].copy_from_slice(b"PE\0\0");
+        header[offset + 4..offset + 6].copy_from_slice(&machine.to_le_bytes());
+        header
+    }
 
-    fs::remove_file(&exe_file).map_err(|_| format!("Failed to delete binary {}", exe_file.display()))?;
-    Ok(())
-}
\ No newline at end of file
+    fn mach_o(magic: u32, cpu_type: u32) -> Vec<u8> {
+        [magic.to_le_bytes(), cpu_type.to_le_bytes()].concat()
+    }
+
+    fn format(kind: ExecutableKind, bits: Option<u8>, arch: Option<&'static str>) -> Option<ExecutableFormat> {
+        Some(ExecutableFormat { kind, bits, arch })
+    }
+
+    #[test]
+    fn detects_elf() {
+        assert_eq!(ExecutableFormat::detect(&elf(64, false, 0x3E)), format(ExecutableKind::Elf, Some(64), Some("x86_64")));
+        assert_eq!(ExecutableFormat::detect(&elf(32, false, 0x03)), format(ExecutableKind::Elf, Some(32), Some("x86")));
+        assert_eq!(ExecutableFormat::detect(&elf(64, false, 0xB7)), format(ExecutableKind::Elf, Some(64), Some("aarch64")));
+        assert_eq!(ExecutableFormat::detect(&elf(32, false, 0xF3)), format(ExecutableKind::Elf, Some(32), Some("riscv32")));
+        assert_eq!(ExecutableFormat::detect(&elf(64, true, 0x16)), format(ExecutableKind::Elf, Some(64), Some("s390x")));
+        assert_eq!(ExecutableFormat::detect(&elf(64, false, 0xFFFF)), format(ExecutableKind::Elf, Some(64), None));
+        assert_eq!(ExecutableFormat::detect(&elf(64, false, 0x3E)[..10]), None);
+    }
+
+    #[test]
+    fn detects_pe() {
+        assert_eq!(ExecutableFormat::detect(&pe(0x80, 0x8664)), format(ExecutableKind::Pe, Some(64), Some("x86_64")));
+        assert_eq!(ExecutableFormat::detect(&pe(0x40, 0x014C)), format(ExecutableKind::Pe, Some(32), Some("x86")));
+        assert_eq!(ExecutableFormat::detect(&pe(0x100, 0xAA64)), format(ExecutableKind::Pe, Some(64), Some("aarch64")));
+
+        // A DOS executable without a PE header
+        let mut dos = pe(0x80, 0x8664);
+        dos[0x80..0x84].copy_from_slice(b"NE\0\0");
+        assert_eq!(ExecutableFormat::detect(&dos), None);
+        assert_eq!(ExecutableFormat::detect(&pe(0x80, 0x8664)[..0x82]), None);
+    }
+
+    #[test]
+    fn detects_mach_o() {
+        assert_eq!(ExecutableFormat::detect(&mach_o(0xFEEDFACF, 0x0100_000C)), format(ExecutableKind::MachO, Some(64), Some("aarch64")));
+        assert_eq!(ExecutableFormat::detect(&mach_o(0xFEEDFACE, 0x0000_0007)), format(ExecutableKind::MachO, Some(32), Some("x86")));
+        // Written big-endian
+        let big_endian = [0xFEEDFACFu32.to_be_bytes(), 0x0100_0007u32.to_be_bytes()].concat();
+        assert_eq!(ExecutableFormat::detect(&big_endian), format(ExecutableKind::MachO, Some(64), Some("x86_64")));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert_eq!(ExecutableFormat::detect(b""), None);
+        assert_eq!(ExecutableFormat::detect(b"#!/bin/sh\necho hi\n"), None);
+        assert_eq!(ExecutableFormat::detect(&[0; 64]), None);
+    }
+
+    #[test]
+    fn runs_on_compatible_machines() {
+        let detect = |binary: &[u8]| ExecutableFormat::detect(binary).unwrap();
+        assert!(detect(&elf(64, false, 0x3E)).runs_on("linux", "x86_64"));
+        assert!(detect(&elf(32, false, 0x03)).runs_on("linux", "x86_64"));
+        assert!(!detect(&elf(64, false, 0x3E)).runs_on("linux", "x86"));
+        assert!(!detect(&elf(64, false, 0xB7)).runs_on("linux", "x86_64"));
+        assert!(!detect(&elf(64, false, 0x3E)).runs_on("windows", "x86_64"));
+        assert!(detect(&pe(0x80, 0x8664)).runs_on("windows", "x86_64"));
+        assert!(!detect(&pe(0x80, 0x8664)).runs_on("linux", "x86_64"));
+        assert!(detect(&mach_o(0xFEEDFACF, 0x0100_000C)).runs_on("macos", "aarch64"));
+    }
+}
@@ -0,0 +1,282 @@
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
+use sha2::{Digest, Sha256};
+use crate::rss_utils::Payload;
+
+/// Per-user cache directory: `$XDG_CACHE_HOME/rss`, `~/.cache/rss` or `%LOCALAPPDATA%\rss\cache`
+pub fn cache_directory() -> Result<PathBuf, String> {
+    #[cfg(target_os = "windows")]
+    let directory = env::var_os("LOCALAPPDATA").map(|d| PathBuf::from(d).join("rss").join("cache"));
+
+    #[cfg(target_os = "linux")]
+    let directory = env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()).map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+        .map(|d| d.join("rss"));
+
+    directory.ok_or_else(|| "Failed to find a cache directory".to_string())
+}
+
+/// Hex SHA-256 of `parts`, used to name cache entries
+pub fn cache_key(parts: &[&[u8]]) -> String {
+    let mut h = Sha256::new();
+    for part in parts {
+        h.update((part.len() as u64).to_le_bytes());
+        h.update(part);
+    }
+    h.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn binary_path(key: &str) -> Result<PathBuf, String> {
+    #[cfg(target_os = "windows")]
+    return Ok(cache_directory()?.join(format!("{key}.exe")));
+
+    #[cfg(target_os = "linux")]
+    return Ok(cache_directory()?.join(key));
+}
+
+/// The path of a cached binary, if there is one. Its modification time is updated so that `prune_cache` keeps binaries
+/// that are still used.
+pub fn get_cached_binary(key: &str) -> Result<Option<PathBuf>, String> {
+    let path = binary_path(key)?;
+    if !path.is_file() {
+        return Ok(None);
+    }
+    if let Ok(file) = File::open(&path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+    Ok(Some(path))
+}
+
+/// Stores an executable binary in the cache, returning its path.
+/// The binary is written to a temporary file and renamed into place so that concurrent runs never see a partial file.
+pub fn store_cached_binary(key: &str, binary: &[u8]) -> Result<PathBuf, String> {
+    let path = binary_path(key)?;
+    let directory = path.parent().unwrap();
+    fs::create_dir_all(directory).map_err(|_| format!("Failed to create cache directory {}", directory.display()))?;
+
+    let temp_path = directory.join(format!("{key}.{}.tmp", process::id()));
+    if let Err(e) = write_executable(&temp_path, binary) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    if fs::rename(&temp_path, &path).is_err() {
+        let _ = fs::remove_file(&temp_path);
+        // Another run may have stored the same binary first, which can't be replaced while it is running on Windows
+        if !path.is_file() {
+            return Err(format!("Failed to write binary to {}", path.display()));
+        }
+    }
+
+    Ok(path)
+}
+
+/// The path of the binary in `payload` in the cache, storing it if it has not been extracted before. The binary is
+/// only decoded if it has to be stored.
+pub fn extract_binary(payload: &Payload) -> Result<PathBuf, String> {
+    let key = payload.cache_key();
+    match get_cached_binary(&key)? {
+        Some(path) => Ok(path),
+        None => store_cached_binary(&key, payload.binary()?)
+    }
+}
+
+fn write_executable(path: &Path, binary: &[u8]) -> Result<(), String> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o755);
+    }
+
+    let mut file = options.open(path).map_err(|_| format!("Failed to create {}", path.display()))?;
+    file.write_all(binary).and_then(|_| file.sync_all()).map_err(|_| format!("Failed to write binary to {}", path.display()))
+}
+
+/// An advisory lock on something shared between runs of rss, released when dropped.
+/// Lock files are kept in the cache as rss files may be replaced or in read-only directories.
+pub struct CacheLock {
+    _file: File
+}
+
+impl CacheLock {
+    /// Locks `rss_file` while it is rebuilt and rewritten, calling `on_wait` first if another run holds the lock
+    pub fn script(rss_file: &Path, on_wait: impl FnOnce()) -> Result<CacheLock, String> {
+        let rss_file = fs::canonicalize(rss_file).unwrap_or_else(|_| rss_file.to_owned());
+        CacheLock::acquire(&cache_key(&[rss_file.as_os_str().as_encoded_bytes()]), false, on_wait)
+    }
+
+    /// Locks the shared target directory so that it can be removed, waiting for every build using it
+    pub fn target_directory(on_wait: impl FnOnce()) -> Result<CacheLock, String> {
+        CacheLock::acquire("target", false, on_wait)
+    }
+
+    /// Locks the shared target directory against removal, and `package` while it is built and its binary is read.
+    /// Cargo only locks the directory while building, so without this scripts with the same package name could swap
+    /// binaries. Scripts with different package names can be built at once.
+    pub fn build(package: &str, on_wait: impl Fn()) -> Result<(CacheLock, CacheLock), String> {
+        let target = CacheLock::acquire("target", true, &on_wait)?;
+        let package = CacheLock::acquire(&format!("package-{}", cache_key(&[package.as_bytes()])), false, &on_wait)?;
+        Ok((target, package))
+    }
+
+    fn acquire(name: &str, shared: bool, on_wait: impl FnOnce()) -> Result<CacheLock, String> {
+        let directory = cache_directory()?.join("locks");
+        fs::create_dir_all(&directory).map_err(|_| format!("Failed to create cache directory {}", directory.display()))?;
+
+        let path = directory.join(format!("{name}.lock"));
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(&path)
+            .map_err(|_| format!("Failed to open lock file {}", path.display()))?;
+
+        let attempt = if shared { file.try_lock_shared() } else { file.try_lock() };
+        match attempt {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                on_wait();
+                let result = if shared { file.lock_shared() } else { file.lock() };
+                result.map_err(|_| format!("Failed to lock {}", path.display()))?;
+            }
+            Err(TryLockError::Error(_)) => return Err(format!("Failed to lock {}", path.display()))
+        }
+
+        Ok(CacheLock { _file: file })
+    }
+}
+
+/// Cargo target directory shared by every build, so dependencies are only compiled once
+pub fn target_directory() -> Result<PathBuf, String> {
+    Ok(cache_directory()?.join("target"))
+}
+
+/// Space used by each part of the cache
+pub struct CacheUsage {
+    /// Size and number of cached binaries
+    pub binaries: (u64, usize),
+    pub build_artifacts: u64,
+    pub history: u64,
+    /// Workspaces and temporary files of running or interrupted runs
+    pub temporary: u64
+}
+
+/// Measures the space used by the cache
+pub fn cache_usage() -> Result<CacheUsage, String> {
+    let directory = cache_directory()?;
+    let mut usage = CacheUsage { binaries: (0, 0), build_artifacts: 0, history: 0, temporary: 0 };
+    for entry in read_cache_directory(&directory)? {
+        let size = directory_size(&entry);
+        match entry.file_name().and_then(|n| n.to_str()) {
+            Some("target") => usage.build_artifacts += size,
+            Some("history") => usage.history += size,
+            Some("locks") => {}
+            Some(name) if name == "workspaces" || name.ends_with(".tmp") => usage.temporary += size,
+            _ => {
+                usage.binaries.0 += size;
+                usage.binaries.1 += 1;
+            }
+        }
+    }
+    Ok(usage)
+}
+
+/// Cached binaries not used for this long are pruned
+const UNUSED_BINARY_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Temporary files and workspaces older than this were left by interrupted runs
+const ABANDONED_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Removes the shared build artifacts, binaries not used for 30 days, or all binaries if `all` is set, and files left by
+/// interrupted runs. Returns the number of bytes freed.
+pub fn prune_cache(all: bool) -> Result<u64, String> {
+    let directory = cache_directory()?;
+    let older_than = |path: &Path, age: Duration| fs::metadata(path).and_then(|m| m.modified()).ok()
+        .and_then(|t| t.elapsed().ok()).is_some_and(|elapsed| elapsed >= age);
+
+    // Held until the target directory has been removed
+    let mut _target_lock = None;
+    let mut removed = Vec::new();
+    for entry in read_cache_directory(&directory)? {
+        match entry.file_name().and_then(|n| n.to_str()) {
+            Some("target") => {
+                _target_lock = Some(CacheLock::target_directory(|| eprintln!("Waiting for builds to finish"))?);
+                removed.push(entry);
+            }
+            Some("history") | Some("locks") => {}
+            // Workspaces of running edit sessions can be left untouched for longer than a day
+            Some("workspaces") => removed.extend(read_cache_directory(&entry)?.into_iter()
+                .filter(|w| older_than(w, ABANDONED_AGE) && !workspace_in_use(w))),
+            Some(name) if name.ends_with(".tmp") => if older_than(&entry, ABANDONED_AGE) { removed.push(entry) },
+            _ => if all || older_than(&entry, UNUSED_BINARY_AGE) { removed.push(entry) }
+        }
+    }
+
+    let mut freed = 0;
+    for path in removed {
+        freed += directory_size(&path);
+        let result = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        result.map_err(|_| format!("Failed to remove {}", path.display()))?;
+    }
+    Ok(freed)
+}
+
+/// Whether the run that created `workspace` is still running, from the process id in its name
+fn workspace_in_use(workspace: &Path) -> bool {
+    let Some(pid) = workspace.file_name().and_then(|n| n.to_str()).and_then(|n| n.rsplit('-').nth(1)) else {
+        return false;
+    };
+    #[cfg(target_os = "linux")]
+    return pid.parse::<u32>().is_ok() && Path::new("/proc").join(pid).exists();
+    // Without a cheap way to check, workspaces are only pruned by age
+    #[cfg(target_os = "windows")]
+    return { let _ = pid; false };
+}
+
+fn read_cache_directory(directory: &Path) -> Result<Vec<PathBuf>, String> {
+    match fs::read_dir(directory) {
+        Ok(entries) => entries.map(|e| e.map(|e| e.path())).collect::<Result<_, _>>()
+            .map_err(|_| format!("Failed read cache directory {}", directory.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(_) => Err(format!("Failed read cache directory {}", directory.display()))
+    }
+}
+
+/// Total size of the files in `path`, not following symlinks
+fn directory_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path).map(|entries| entries.filter_map(Result::ok).map(|e| directory_size(&e.path())).sum()).unwrap_or(0)
+}
+
+/// Formats a number of bytes with binary units
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{bytes} B") } else { format!("{size:.1} {}", UNITS[unit]) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn keeps_workspaces_of_running_processes() {
+        assert!(workspace_in_use(Path::new(&format!("/cache/workspaces/my-script-{}-0", std::process::id()))));
+        assert!(!workspace_in_use(Path::new(&format!("/cache/workspaces/my-script-{}-0", u32::MAX))));
+        assert!(!workspace_in_use(Path::new("/cache/workspaces/script")));
+    }
+}
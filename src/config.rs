@@ -1,6 +1,8 @@
 use std::path::{Path, PathBuf};
 use std::fs;
 use derive_getters::Getters;
+use crate::binary_utils::ExecutionStrategy;
+use crate::project_utils::WorkspaceLocation;
 
 pub enum Editor {
     Code,
@@ -23,8 +25,21 @@ impl Editor {
 pub struct Config {
     check_hash: bool,
     base64: bool,
-    new_dir: bool,
-    editor: Editor
+    /// Where projects are generated and built
+    workspace: WorkspaceLocation,
+    editor: Editor,
+    /// Deflate level (0-9) for embedded binaries, or `None` to store them uncompressed
+    compression: Option<u32>,
+    /// Whether to store binaries in rss files, rather than only the source with binaries kept in the user cache
+    embed_binary: bool,
+    /// How embedded binaries are executed
+    execution: ExecutionStrategy,
+    /// Whether to keep the previous version of an rss file as `.rss.bak` when rewriting it
+    backup: bool,
+    /// Number of previous versions of each rss file kept for `rss history` and `rss rollback`
+    history: usize,
+    /// Cargo profile used for scripts that don't set one in `[package.metadata.rss]`
+    profile: String
 }
 
 impl Config {
@@ -38,7 +53,7 @@ impl Config {
             return Err(format!("Failed to open config file [{:?}]", config_location))
         };
 
-        const OPTIONS_COUNT: usize = 4;
+        const OPTIONS_COUNT: usize = 10;
         let mut options = Vec::with_capacity(OPTIONS_COUNT);
 
         for i in 0..OPTIONS_COUNT {
@@ -53,14 +68,36 @@ impl Config {
 
         let check_hash = options[0] == "true";
         let base64 = options[1] == "true";
-        let new_dir = options[2] == "true";
+        let workspace = WorkspaceLocation::from_str(options[2])?;
         let editor = Editor::from_str(options[3])?;
+        let compression = match options[4] {
+            "none" => None,
+            level => match level.parse() {
+                Ok(level) if level <= 9 => Some(level),
+                _ => return Err(format!("Compression level [{level}] in config file must be 0-9 or none"))
+            }
+        };
+        let embed_binary = options[5] == "true";
+        let execution = ExecutionStrategy::from_str(options[6])?;
+        let backup = options[7] == "true";
+        let history = options[8].parse()
+            .map_err(|_| format!("History size [{}] in config file must be a number", options[8]))?;
+        let profile = match options[9] {
+            "" => return Err("Profile in config file must not be empty".to_string()),
+            profile => profile.to_string()
+        };
 
         Ok(Config {
             check_hash,
             base64,
-            new_dir,
+            workspace,
             editor,
+            compression,
+            embed_binary,
+            execution,
+            backup,
+            history,
+            profile,
         })
     }
 }
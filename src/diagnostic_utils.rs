@@ -0,0 +1,179 @@
+use std::path::Path;
+
+/// The generated source file that diagnostics are reported against
+const SOURCE_FILE: &str = "src/main.rs";
+/// The generated manifest that Cargo reports errors against
+const MANIFEST_FILE: &str = "Cargo.toml";
+
+/// Maps diagnostics for the generated project onto the rss file it was generated from
+pub struct SourceMap<'a> {
+    rss_file: &'a Path,
+    /// Line of the rss file that the source starts on
+    source_line: usize
+}
+
+impl SourceMap<'_> {
+    /// The manifest starts after the `/*` on the first line and the source on the line after the manifest's `*/`
+    pub fn new<'a>(rss_file: &'a Path, cargo_content: &str) -> SourceMap<'a> {
+        SourceMap { rss_file, source_line: cargo_content.matches('\n').count() + 2 }
+    }
+
+    /// Maps a `file:line:column` location in the generated project, returning `None` for other files
+    fn map_location(&self, location: &str) -> Option<String> {
+        let mut parts = location.rsplitn(3, ':');
+        let column = parts.next()?.parse::<usize>().ok()?;
+        let line = parts.next()?.parse::<usize>().ok()?;
+        let (line, column) = match parts.next()? {
+            SOURCE_FILE => (line + self.source_line - 1, column),
+            // Only the first line of the manifest shares its line with the `/*` delimiter
+            MANIFEST_FILE => (line, if line == 1 { column + 2 } else { column }),
+            _ => return None
+        };
+        Some(format!("{}:{line}:{column}", self.rss_file.display()))
+    }
+
+    /// Rewrites a diagnostic rendered by rustc so that its locations and line numbers point into the rss file.
+    /// Line numbers in the margin are shifted for snippets of the source and the margin is widened to fit them.
+    pub fn map_rendered(&self, rendered: &str) -> String {
+        // Every margin in a diagnostic has the width of the `-->` line's indentation
+        let Some(width) = rendered.lines().find_map(|l| {
+            let trimmed = l.trim_start_matches(' ');
+            trimmed.starts_with("--> ").then_some(l.len() - trimmed.len())
+        }) else {
+            return rendered.to_string();
+        };
+
+        enum Margin<'a> {
+            Location(&'a str, &'a str),
+            Numbered(usize, &'a str),
+            Blank(&'a str),
+            None(&'a str)
+        }
+
+        let mut in_source = false;
+        let mut lines = Vec::new();
+        for line in rendered.split_inclusive('\n') {
+            let (margin, rest) = line.split_at(width.min(line.len()));
+            let margin = margin.trim_start_matches(' ');
+            let is_margin = rest.starts_with(" |") || rest.starts_with(" =");
+
+            lines.push(if margin.is_empty() && (rest.starts_with("--> ") || rest.starts_with("::: ")) {
+                let (arrow, location) = rest.split_at(4);
+                let location_end = location.trim_end().len();
+                in_source = location[..location_end].starts_with(SOURCE_FILE);
+                Margin::Location(arrow, location)
+            }
+            else if is_margin && margin.is_empty() {
+                Margin::Blank(rest)
+            }
+            else if let Some(number) = margin.parse::<usize>().ok().filter(|_| is_margin) {
+                Margin::Numbered(if in_source { number + self.source_line - 1 } else { number }, rest)
+            }
+            else {
+                Margin::None(line)
+            });
+        }
+
+        let width = lines.iter().filter_map(|l| match l {
+            Margin::Numbered(number, _) => Some(number.to_string().len()),
+            _ => None
+        }).fold(width, usize::max);
+
+        lines.into_iter().map(|line| match line {
+            Margin::Location(arrow, location) => {
+                let (location, end) = location.split_at(location.trim_end().len());
+                let location = self.map_location(location).unwrap_or_else(|| location.to_string());
+                format!("{:width$}{arrow}{location}{end}", "")
+            }
+            Margin::Numbered(number, rest) => format!("{number:>width$}{rest}"),
+            Margin::Blank(rest) => format!("{:width$}{rest}", ""),
+            Margin::None(line) => line.to_string()
+        }).collect()
+    }
+
+    /// Rewrites a line of Cargo's own output, such as a manifest error, to point into the rss file
+    pub fn map_cargo_line(&self, line: &str, workspace: &Path) -> String {
+        let workspace = format!("{}{}", workspace.display(), std::path::MAIN_SEPARATOR);
+        let line = line.replace(&format!("{workspace}{MANIFEST_FILE}"), &self.rss_file.display().to_string())
+            .replace(&format!("{workspace}{SOURCE_FILE}"), &self.rss_file.display().to_string());
+
+        let trimmed = line.trim_start_matches(' ');
+        match trimmed.strip_prefix("--> ").and_then(|l| self.map_location(l)) {
+            Some(location) => format!("{}--> {location}", &line[..line.len() - trimmed.len()]),
+            None => line
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARGO: &str = "[package]\nname = \"test\"\nversion = \"0.1.0\"\n";
+
+    #[test]
+    fn maps_source_diagnostic() {
+        let map = SourceMap::new(Path::new("/scripts/test.rss"), CARGO);
+        let rendered = "error[E0425]: cannot find value `nope` in this scope\n \
+                        --> src/main.rs:2:5\n  \
+                        |\n\
+                        2 |     nope\n  \
+                        |     ^^^^ not found in this scope\n\n";
+        assert_eq!(map.map_rendered(rendered), "error[E0425]: cannot find value `nope` in this scope\n \
+                                                --> /scripts/test.rss:6:5\n  \
+                                                |\n\
+                                                6 |     nope\n  \
+                                                |     ^^^^ not found in this scope\n\n");
+    }
+
+    #[test]
+    fn widens_margin() {
+        let map = SourceMap::new(Path::new("test.rss"), CARGO);
+        let rendered = "warning: unused variable: `x`\n \
+                        --> src/main.rs:8:9\n  \
+                        |\n\
+                        8 |     let x = 1;\n  \
+                        |         ^ help: if this is intentional, prefix it with an underscore: `_x`\n  \
+                        |\n  \
+                        = note: `#[warn(unused_variables)]` on by default\n";
+        assert_eq!(map.map_rendered(rendered), "warning: unused variable: `x`\n  \
+                                                --> test.rss:12:9\n   \
+                                                |\n\
+                                                12 |     let x = 1;\n   \
+                                                |         ^ help: if this is intentional, prefix it with an underscore: `_x`\n   \
+                                                |\n   \
+                                                = note: `#[warn(unused_variables)]` on by default\n");
+    }
+
+    #[test]
+    fn keeps_other_files() {
+        let map = SourceMap::new(Path::new("test.rss"), CARGO);
+        let rendered = "error: x\n \
+                        --> src/main.rs:1:1\n  \
+                        |\n\
+                        1 | x\n  \
+                        |\n \
+                        ::: /rustc/library/core/src/lib.rs:9:1\n  \
+                        |\n\
+                        9 | y\n";
+        assert_eq!(map.map_rendered(rendered), "error: x\n \
+                                                --> test.rss:5:1\n  \
+                                                |\n\
+                                                5 | x\n  \
+                                                |\n \
+                                                ::: /rustc/library/core/src/lib.rs:9:1\n  \
+                                                |\n\
+                                                9 | y\n");
+    }
+
+    #[test]
+    fn maps_manifest_errors() {
+        let map = SourceMap::new(Path::new("test.rss"), CARGO);
+        let workspace = Path::new("/cache/workspaces/test-1-0");
+        assert_eq!(map.map_cargo_line(" --> Cargo.toml:3:11", workspace), " --> test.rss:3:11");
+        assert_eq!(map.map_cargo_line(" --> Cargo.toml:1:1", workspace), " --> test.rss:1:3");
+        assert_eq!(map.map_cargo_line(&format!("error: failed to parse manifest at `{}`", workspace.join("Cargo.toml").display()), workspace),
+                   "error: failed to parse manifest at `test.rss`");
+        assert_eq!(map.map_cargo_line("   Compiling test v0.1.0", workspace), "   Compiling test v0.1.0");
+    }
+}
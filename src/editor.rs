@@ -4,12 +4,9 @@ use std::process::Command;
 
 use crate::config::{Config, Editor};
 
-pub fn start_editor_blocking(config: &Config, rss_file: &Path) -> Result<(), String> {
-    let mut directory = rss_file.parent().unwrap().to_path_buf();
-    let file_name = rss_file.file_stem().unwrap();
-    if *config.new_dir() {
-        directory = directory.join(file_name);
-    }
+/// Opens the project in `workspace` and waits for the editor to close
+pub fn start_editor_blocking(config: &Config, workspace: &Path) -> Result<(), String> {
+    let directory = workspace;
     let main_file = directory.join("src").join("main.rs");
 
     match config.editor() {
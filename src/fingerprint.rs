@@ -0,0 +1,277 @@
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use std::process::Command;
+use derive_getters::Getters;
+
+/// Target triple that rss itself was built for, used when rustc is not available
+const HOST_TARGET: &str = env!("RSS_HOST_TARGET");
+
+/// Describes the toolchain and settings an embedded binary was built with.
+/// Written to the trailer as `rustc=<version>;target=<triple>;profile=<profile>;features=<a>,<b>`, followed by
+/// `;no-default-features=true`, `;rustflags=<flags>` and `;cargo-args=<a>,<b>` if they are set.
+#[derive(Getters, Clone, PartialEq, Eq, Debug)]
+pub struct Fingerprint {
+    /// `None` if rustc could not be found
+    rustc: Option<String>,
+    target: String,
+    profile: String,
+    /// Sorted, without duplicates
+    features: Vec<String>,
+    no_default_features: bool,
+    /// `RUSTFLAGS` for the build
+    rustflags: String,
+    /// Extra arguments passed to `cargo build`
+    cargo_args: Vec<String>
+}
+
+impl Fingerprint {
+    /// The fingerprint that a build in `directory` would have. Builds run in the workspace directory, so that is where
+    /// rustc is asked for its version, letting rustup pick the same toolchain as for the build.
+    pub fn current(directory: &Path) -> Fingerprint {
+        let rustc_info = Command::new("rustc").arg("-vV").current_dir(directory).output().ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).to_string());
+
+        let field = |name: &str| rustc_info.as_ref().and_then(|info| {
+            info.lines().find_map(|l| l.strip_prefix(name)).map(|v| v.trim().to_string())
+        });
+
+        let rustc = field("release:").map(|release| match field("commit-hash:") {
+            Some(commit) if commit != "unknown" => format!("{release} ({})", &commit[..commit.len().min(9)]),
+            _ => release
+        });
+
+        let fingerprint = Fingerprint { rustc, ..Fingerprint::unprobed() };
+        match field("host:") {
+            Some(host) => fingerprint.with_target(&host),
+            None => fingerprint
+        }
+    }
+
+    /// The fingerprint of a build for the target rss was built for, without running rustc. Only good for finding a
+    /// binary when its toolchain doesn't matter.
+    pub fn unprobed() -> Fingerprint {
+        Fingerprint {
+            rustc: None,
+            target: HOST_TARGET.to_string(),
+            profile: "release".to_string(),
+            features: Vec::new(),
+            no_default_features: false,
+            rustflags: String::new(),
+            cargo_args: Vec::new()
+        }
+    }
+
+    /// The fingerprint of a build for a different target
+    pub fn with_target(mut self, target: &str) -> Fingerprint {
+        self.target = target.to_string();
+        self
+    }
+
+    /// The fingerprint of a build with a different Cargo profile
+    pub fn with_profile(mut self, profile: &str) -> Fingerprint {
+        self.profile = profile.to_string();
+        self
+    }
+
+    /// The fingerprint of a build with different Cargo features. Features may be separated by commas or spaces.
+    pub fn with_features(mut self, features: &[String], no_default_features: bool) -> Fingerprint {
+        let mut features = features.iter().flat_map(|f| f.split([',', ' '])).filter(|f| !f.is_empty()).map(|f| f.to_string()).collect::<Vec<_>>();
+        features.sort_unstable();
+        features.dedup();
+        self.features = features;
+        self.no_default_features = no_default_features;
+        self
+    }
+
+    /// The fingerprint of a build with different `RUSTFLAGS` and extra Cargo arguments
+    pub fn with_flags(mut self, rustflags: &str, cargo_args: &[String]) -> Fingerprint {
+        self.rustflags = rustflags.trim().to_string();
+        self.cargo_args = cargo_args.to_vec();
+        self
+    }
+
+    /// Describes how this fingerprint differs from `current`. Unknown values in `current` are not compared.
+    pub fn differences(&self, current: &Fingerprint) -> Vec<String> {
+        let mut differences = Vec::new();
+        if let Some(rustc) = &current.rustc {
+            if self.rustc.as_ref() != Some(rustc) {
+                differences.push(format!("rustc {} (current: {rustc})", self.rustc.as_deref().unwrap_or("unknown")));
+            }
+        }
+        if self.target != current.target {
+            differences.push(format!("target {} (current: {})", self.target, current.target));
+        }
+        if self.profile != current.profile {
+            differences.push(format!("profile {} (current: {})", self.profile, current.profile));
+        }
+        if self.features != current.features {
+            differences.push(format!("features [{}] (current: [{}])", self.features.join(", "), current.features.join(", ")));
+        }
+        if self.no_default_features != current.no_default_features {
+            differences.push(format!("default features {} (current: {})", on_off(!self.no_default_features), on_off(!current.no_default_features)));
+        }
+        if self.rustflags != current.rustflags {
+            differences.push(format!("RUSTFLAGS [{}] (current: [{}])", self.rustflags, current.rustflags));
+        }
+        if self.cargo_args != current.cargo_args {
+            differences.push(format!("Cargo arguments [{}] (current: [{}])", self.cargo_args.join(" "), current.cargo_args.join(" ")));
+        }
+        differences
+    }
+
+    pub fn parse(field: &str) -> Option<Fingerprint> {
+        let mut rustc = None;
+        let mut target = None;
+        let mut profile = None;
+        let mut features = None;
+        let mut no_default_features = false;
+        let mut rustflags = String::new();
+        let mut cargo_args = Vec::new();
+
+        for pair in field.split(';') {
+            let (key, raw) = pair.split_once('=')?;
+            let value = unescape(raw)?;
+            match key {
+                "rustc" => rustc = Some(value),
+                "target" => target = Some(value),
+                "profile" => profile = Some(value),
+                "features" => features = Some(split_list(raw)?),
+                "no-default-features" => no_default_features = value == "true",
+                "rustflags" => rustflags = value,
+                "cargo-args" => cargo_args = split_list(raw)?,
+                // Allow newer versions to add fields
+                _ => {}
+            }
+        }
+
+        Some(Fingerprint {
+            rustc,
+            target: target?,
+            profile: profile?,
+            features: features?,
+            no_default_features,
+            rustflags,
+            cargo_args
+        })
+    }
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(rustc) = &self.rustc {
+            write!(f, "rustc={};", escape(rustc))?;
+        }
+        write!(
+            f, "target={};profile={};features={}",
+            escape(&self.target),
+            escape(&self.profile),
+            join_list(&self.features)
+        )?;
+        // Only written when set so that fingerprints from before they existed stay the same
+        if self.no_default_features {
+            write!(f, ";no-default-features=true")?;
+        }
+        if !self.rustflags.is_empty() {
+            write!(f, ";rustflags={}", escape(&self.rustflags))?;
+        }
+        if !self.cargo_args.is_empty() {
+            write!(f, ";cargo-args={}", join_list(&self.cargo_args))?;
+        }
+        Ok(())
+    }
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+/// Joins values with `,` after escaping them
+fn join_list(values: &[String]) -> String {
+    values.iter().map(|v| escape(v)).collect::<Vec<_>>().join(",")
+}
+
+/// Splits and unescapes a list joined by `join_list`
+fn split_list(value: &str) -> Option<Vec<String>> {
+    value.split(',').filter(|v| !v.is_empty()).map(unescape).collect()
+}
+
+/// Whether binaries built for the `target` triple can run on a `host` with another triple. The architecture and
+/// operating system must match. On Linux the environment must match too, unless the binary is for musl, which is linked
+/// statically and so also runs on glibc hosts, while glibc binaries don't run on musl hosts.
+pub fn runs_on(target: &str, host: &str) -> bool {
+    // Triples are <arch>-<vendor>-<os>[-<environment>], though some leave out the vendor
+    const SYSTEMS: [&str; 8] = ["android", "windows", "darwin", "ios", "linux", "freebsd", "netbsd", "openbsd"];
+    let system = |triple: &str| SYSTEMS.into_iter().find(|s| triple.split('-').skip(1).any(|p| p.starts_with(s)));
+
+    if target == host {
+        return true;
+    }
+    if target.split('-').next() != host.split('-').next() || system(target).is_none() || system(target) != system(host) {
+        return false;
+    }
+    system(target) != Some("linux") || target.rsplit('-').next().is_some_and(|environment| environment.starts_with("musl"))
+}
+
+/// Percent-encodes characters that have a meaning in the trailer
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '%' | ':' | ';' | '=' | ',' | '*' | '/') || c.is_control() {
+            for b in c.to_string().bytes() {
+                escaped.push_str(&format!("%{b:02X}"));
+            }
+        }
+        else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn unescape(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        }
+        else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_settings_round_trip() {
+        let plain = Fingerprint::parse("target=x86_64-unknown-linux-gnu;profile=release;features=").unwrap();
+        assert_eq!(plain.to_string(), "target=x86_64-unknown-linux-gnu;profile=release;features=");
+
+        let fingerprint = plain.clone()
+            .with_features(&["json,tracing".to_string(), "color json".to_string()], true)
+            .with_flags(" -C target-cpu=native ", &["--locked".to_string(), "--config=a,b".to_string()]);
+        assert_eq!(fingerprint.features(), &["color", "json", "tracing"]);
+        assert_eq!(fingerprint.rustflags(), "-C target-cpu=native");
+        assert_eq!(Fingerprint::parse(&fingerprint.to_string()).unwrap(), fingerprint);
+        assert_eq!(fingerprint.differences(&plain).len(), 4);
+    }
+
+    #[test]
+    fn runs_on_compatible_hosts() {
+        assert!(runs_on("x86_64-unknown-linux-gnu", "x86_64-unknown-linux-gnu"));
+        assert!(runs_on("x86_64-unknown-linux-musl", "x86_64-unknown-linux-gnu"));
+        assert!(!runs_on("x86_64-unknown-linux-gnu", "x86_64-unknown-linux-musl"));
+        assert!(runs_on("armv7-unknown-linux-musleabihf", "armv7-unknown-linux-gnueabihf"));
+        assert!(runs_on("x86_64-pc-windows-gnu", "x86_64-pc-windows-msvc"));
+        assert!(!runs_on("aarch64-unknown-linux-gnu", "x86_64-unknown-linux-gnu"));
+        assert!(!runs_on("x86_64-pc-windows-msvc", "x86_64-unknown-linux-gnu"));
+        assert!(!runs_on("aarch64-linux-android", "aarch64-unknown-linux-gnu"));
+        assert!(!runs_on("wasm32-unknown-unknown", "wasm32-wasip1"));
+    }
+}
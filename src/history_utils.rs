@@ -0,0 +1,161 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::cache_utils::{cache_directory, cache_key};
+use crate::rss_utils::RssFile;
+
+/// A previous version of an rss file, kept in the history store
+pub struct Version {
+    pub path: PathBuf,
+    /// When the version was replaced
+    pub time: SystemTime
+}
+
+/// The history store for `rss_file`, keyed by its canonical path
+fn history_directory(rss_file: &Path) -> Result<PathBuf, String> {
+    let rss_file = fs::canonicalize(rss_file).unwrap_or_else(|_| rss_file.to_path_buf());
+    Ok(cache_directory()?.join("history").join(cache_key(&[rss_file.as_os_str().as_encoded_bytes()])))
+}
+
+/// Versions are named `<seconds>-<nanoseconds>-<content hash>.rss` so that they sort oldest first
+fn parse_version_name(name: &str) -> Option<(SystemTime, &str)> {
+    let mut parts = name.strip_suffix(".rss")?.splitn(3, '-');
+    let seconds = parts.next()?.parse().ok()?;
+    let nanoseconds = parts.next()?.parse().ok()?;
+    let hash = parts.next()?;
+    Some((UNIX_EPOCH + Duration::new(seconds, nanoseconds), hash))
+}
+
+/// Previous versions of `rss_file`, newest first
+pub fn versions(rss_file: &Path) -> Result<Vec<Version>, String> {
+    let directory = history_directory(rss_file)?;
+    let Ok(entries) = fs::read_dir(&directory) else {
+        return Ok(Vec::new());
+    };
+
+    let mut names = entries.filter_map(|e| e.ok()?.file_name().into_string().ok())
+        .filter(|n| parse_version_name(n).is_some())
+        .collect::<Vec<_>>();
+    names.sort_unstable_by(|a, b| b.cmp(a));
+
+    Ok(names.into_iter().map(|name| Version {
+        time: parse_version_name(&name).unwrap().0,
+        path: directory.join(name)
+    }).collect())
+}
+
+/// Records `contents` as the version of `rss_file` being replaced, keeping at most `limit` versions.
+/// Nothing is recorded if `limit` is 0, the file is empty or the contents match the newest version.
+pub fn record_version(rss_file: &Path, contents: &[u8], limit: usize) -> Result<(), String> {
+    if limit == 0 || contents.is_empty() {
+        return Ok(());
+    }
+
+    let hash = cache_key(&[contents]);
+    let hash = &hash[..16];
+    let existing = versions(rss_file)?;
+    let newest_hash = existing.first().and_then(|v| parse_version_name(v.path.file_name()?.to_str()?).map(|(_, h)| h.to_string()));
+    if newest_hash.as_deref() != Some(hash) {
+        let directory = history_directory(rss_file)?;
+        fs::create_dir_all(&directory).map_err(|_| format!("Failed to create history directory {}", directory.display()))?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let path = directory.join(format!("{:020}-{:09}-{hash}.rss", now.as_secs(), now.subsec_nanos()));
+        fs::write(&path, contents).map_err(|_| format!("Failed to record history in {}", path.display()))?;
+    }
+
+    for version in versions(rss_file)?.iter().skip(limit) {
+        fs::remove_file(&version.path).map_err(|_| format!("Failed to remove old history {}", version.path.display()))?;
+    }
+    Ok(())
+}
+
+/// Moves the history of a script that was moved from `from` to `to`
+pub fn move_history(from: &Path, to: &Path) -> Result<(), String> {
+    let from = history_directory(from)?;
+    let to = history_directory(to)?;
+    let Ok(entries) = fs::read_dir(&from) else {
+        return Ok(());
+    };
+    if from == to {
+        return Ok(());
+    }
+
+    fs::create_dir_all(&to).map_err(|_| format!("Failed to create history directory {}", to.display()))?;
+    for entry in entries.filter_map(Result::ok) {
+        fs::rename(entry.path(), to.join(entry.file_name()))
+            .map_err(|_| format!("Failed to move history {}", entry.path().display()))?;
+    }
+    fs::remove_dir(&from).map_err(|_| format!("Failed to remove history directory {}", from.display()))
+}
+
+/// The manifest and source of an rss file, for comparing versions without their payloads
+pub fn source_text(contents: &[u8]) -> String {
+    match RssFile::parse_lazily(contents) {
+        Ok(rss) => format!("{}\n{}", rss.cargo_content(), rss.rust_content()),
+        Err(_) => String::from_utf8_lossy(contents).to_string()
+    }
+}
+
+/// Lines added and removed going from `old` to `new`, from their longest common subsequence
+pub fn diff_stats(old: &str, new: &str) -> (usize, usize) {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // Unchanged lines at either end don't need comparing
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+
+    let mut row = vec![0; new.len() + 1];
+    for a in old {
+        let mut diagonal = 0;
+        for (j, b) in new.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if a == b { diagonal + 1 } else { above.max(row[j]) };
+            diagonal = above;
+        }
+    }
+    let common = row[new.len()];
+    (new.len() - common, old.len() - common)
+}
+
+/// Formats `time` as `YYYY-MM-DD HH:MM:SS UTC`
+pub fn format_time(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_stats_counts_changed_lines() {
+        assert_eq!(diff_stats("a\nb\nc", "a\nb\nc"), (0, 0));
+        assert_eq!(diff_stats("a\nb\nc", "a\nx\nc\nd"), (2, 1));
+        assert_eq!(diff_stats("", "a\nb"), (2, 0));
+        assert_eq!(diff_stats("a\nb\na\nb", "b\na\nb\na"), (1, 1));
+    }
+
+    #[test]
+    fn format_time_in_utc() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_time(UNIX_EPOCH + Duration::from_secs(951_782_400 + 3661)), "2000-02-29 01:01:01 UTC");
+        assert_eq!(format_time(UNIX_EPOCH + Duration::from_secs(1_767_225_599)), "2025-12-31 23:59:59 UTC");
+    }
+}
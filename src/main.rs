@@ -1,12 +1,20 @@
-use std::{env, fs, path, process};
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::path::PathBuf;
+use std::{env, fs, path, process, thread};
+use std::sync::Mutex;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::vec::IntoIter;
 use config::Config;
-use crate::binary_utils::{delete_binary, execute_binary, write_binary};
+use crate::cache_utils::{cache_directory, cache_usage, extract_binary, format_size, get_cached_binary, prune_cache, store_cached_binary, CacheLock};
+use crate::binary_utils::{check_executable_format, execute_binary, exit_with_status};
+#[cfg(target_os = "linux")]
+use crate::binary_utils::{execute_binary_in_memory, ExecutionStrategy};
 use crate::editor::start_editor_blocking;
-use crate::project_utils::{build_project, delete_project, generate_project, get_cargo_and_source_project};
-use crate::rss_utils::{build_rss, check_file, get_binary_rss, get_cargo_and_source_rss};
+use crate::fingerprint::Fingerprint;
+use crate::diagnostic_utils::SourceMap;
+use crate::project_utils::{build_project, choose_binary, create_workspace, delete_project, generate_project, get_cargo_and_source_project, workspace_directory};
+use crate::signal_utils::{exit_if_interrupted, forward_signals, is_interrupted};
+use crate::rss_utils::{check_file, create_rss_file, line_column, package_name, rss_metadata, write_rss_file, Compression, Payload, PayloadEncoding, RssFile};
+use crate::history_utils::{diff_stats, format_time, move_history, source_text, versions};
 
 mod config;
 mod rss_utils;
@@ -14,25 +22,32 @@ mod project_utils;
 mod editor;
 mod binary_utils;
 mod os_str_utils;
+mod fingerprint;
+mod cache_utils;
+mod signal_utils;
+mod history_utils;
+mod diagnostic_utils;
 
-const HELP_TEXT: &'static str = include_str!("help_text");
+const HELP_TEXT: &str = include_str!("help_text");
 
 fn print_err_exit(s: Option<&str>, help_text: bool) -> ! {
+    // Errors caused by an interruption are not worth reporting
+    exit_if_interrupted();
     if let Some(s) = s {
-        println!("{s}");
-        if help_text { println!(); }
+        eprintln!("{s}");
+        if help_text { eprintln!(); }
     }
     if help_text {
-        println!("{HELP_TEXT}");
+        eprintln!("{HELP_TEXT}");
     }
     process::exit(-1)
 }
 
-fn get_file(args: &mut IntoIter<String>, generate: bool) -> Result<PathBuf, String> {
+fn get_file(args: &mut IntoIter<OsString>, generate: bool) -> Result<PathBuf, String> {
     if let Some(file) = args.next() {
         let f = path::absolute(PathBuf::from(file)).unwrap();
         if generate && !f.is_file() {
-            fs::write(&f, &[]).map_err(|_| format!("Failed to create file [{}]", f.display()))?;
+            fs::write(&f, []).map_err(|_| format!("Failed to create file [{}]", f.display()))?;
         }
         Ok(f)
     } else {
@@ -40,107 +55,724 @@ fn get_file(args: &mut IntoIter<String>, generate: bool) -> Result<PathBuf, Stri
     }
 }
 
+/// Build settings given on the command line, each replacing the script's own
+#[derive(Default)]
+struct BuildArgs {
+    profile: Option<String>,
+    features: Option<Vec<String>>,
+    no_default_features: Option<bool>,
+    rustflags: Option<String>,
+    cargo_args: Option<Vec<String>>
+}
+
+impl BuildArgs {
+    /// Handles `arg`, taking its value from `args`, if it is a build setting. Returns whether it was one.
+    fn parse(&mut self, arg: &OsStr, args: &mut IntoIter<OsString>) -> Result<bool, String> {
+        let arg = arg.to_string_lossy();
+        let mut value = || args.next().and_then(|v| v.into_string().ok()).ok_or_else(|| format!("{arg} requires a value"));
+        match arg.as_ref() {
+            "--profile" => self.profile = Some(value()?),
+            "--features" | "-F" => self.features.get_or_insert_with(Vec::new).push(value()?),
+            "--no-default-features" => self.no_default_features = Some(true),
+            "--default-features" => self.no_default_features = Some(false),
+            "--rustflags" => self.rustflags = Some(value()?),
+            "--cargo-arg" => self.cargo_args.get_or_insert_with(Vec::new).push(value()?),
+            "--no-cargo-args" => self.cargo_args = Some(Vec::new()),
+            _ => return Ok(false)
+        }
+        Ok(true)
+    }
+
+    /// Takes the build settings before the file in the arguments of a command
+    fn take(args: &mut IntoIter<OsString>) -> Result<BuildArgs, String> {
+        let mut build_args = BuildArgs::default();
+        while let Some(arg) = args.as_slice().first().cloned() {
+            let mut rest = args.clone();
+            rest.next();
+            if !build_args.parse(&arg, &mut rest)? {
+                break;
+            }
+            *args = rest;
+        }
+        Ok(build_args)
+    }
+}
+
+/// Collects the arguments to forward to a script, dropping a single leading `--` separator
+fn get_script_args(args: IntoIter<OsString>) -> Vec<OsString> {
+    let mut args = args.peekable();
+    if args.peek().is_some_and(|a| a == "--") {
+        args.next();
+    }
+    args.collect()
+}
+
+/// Generates and builds the project for the script, always cleaning up the project files. A binary the user chooses
+/// is recorded in the manifest of `rss`, which the caller writes.
+fn build_binary(config: &Config, rss_file: &Path, rss: &mut RssFile, fingerprint: &Fingerprint) -> Result<Vec<u8>, Result<(), String>> {
+    let file_name = rss_file.file_stem().unwrap().to_string_lossy();
+    if let Some(name) = package_name(rss.cargo_content()).filter(|n| *n != file_name) {
+        eprintln!("Package [{name}] is not named after [{}], rss mv and rss cp rename both together", rss_file.display());
+    }
+    let workspace = create_workspace(config, rss_file).map_err(Err)?;
+
+    eprintln!("Generating project files");
+    let binary = generate_project(&workspace, rss.cargo_content(), rss.rust_content())
+        .and_then(|()| choose_binary(&workspace, rss_file)).map_err(Err)
+        .and_then(|chosen| {
+            if let Some(cargo_content) = chosen {
+                let binaries = |rss: &RssFile| rss.payloads().iter().filter(|p| !p.is_source_only()).count();
+                let before = binaries(rss);
+                rss.set_source(cargo_content, rss.rust_content().clone());
+                let removed = before - binaries(rss);
+                if removed != 0 {
+                    eprintln!("Removing {removed} binaries built from the previous version");
+                }
+                if rss.payloads().is_empty() {
+                    rss.set_source_only(Payload::source_only(rss.cargo_content(), rss.rust_content(), fingerprint.clone()));
+                }
+            }
+            eprintln!("Building project");
+            build_project(&workspace, rss_file, fingerprint, Some(&SourceMap::new(rss_file, rss.cargo_content())))
+        });
+
+    eprintln!("Cleaning project files");
+    delete_project(&workspace).map_err(Err)?;
+    // Other builds may still be cleaning up, so the caller decides when to exit
+    if is_interrupted() {
+        return Err(Err("Build interrupted".to_string()));
+    }
+    binary
+}
+
+/// Copies or moves a script to `to`, which must not exist. A package named after the old file is renamed after the
+/// new one, and the history of a moved script moves with it.
+fn copy_script(config: &Config, from: &Path, to: &Path, keep_original: bool) -> Result<(), String> {
+    if to.exists() {
+        return Err(format!("[{}] already exists", to.display()));
+    }
+    let _lock = wait_for_lock(from);
+
+    let original = fs::metadata(from).map_err(|_| format!("Failed read [{}]", from.display()))?;
+    let mut contents = fs::read(from).map_err(|_| format!("Failed read [{}]", from.display()))?;
+    let mut rss = RssFile::read(from)?;
+
+    let old_name = from.file_stem().unwrap().to_string_lossy();
+    let new_name = to.file_stem().ok_or("Destination has no file name")?.to_string_lossy();
+    let mut renamed = false;
+    // An empty file is named after whatever file it ends up as when it is first edited
+    match package_name(rss.cargo_content()).filter(|_| !contents.is_empty()) {
+        Some(name) if name == old_name && name != new_name => {
+            let had_binaries = rss.rename_package(&new_name).unwrap();
+            eprintln!("Renaming package [{old_name}] to [{new_name}]");
+            if had_binaries {
+                eprintln!("Removed the binaries built under the old name, they will be rebuilt when needed");
+            }
+            if rss.payloads().is_empty() {
+                rss.set_source_only(Payload::source_only(rss.cargo_content(), rss.rust_content(), Fingerprint::current(&workspace_directory(config)?)));
+            }
+            contents = rss.serialize();
+            renamed = true;
+        }
+        Some(name) if name != new_name => eprintln!("Keeping package name [{name}], which is not named after [{}]", from.display()),
+        _ => {}
+    }
+
+    if keep_original {
+        return create_rss_file(to, &contents, &original);
+    }
+
+    let from = fs::canonicalize(from).unwrap_or_else(|_| from.to_path_buf());
+    fs::rename(&from, to).or_else(|_| {
+        // Renaming fails across file systems
+        create_rss_file(to, &fs::read(&from).map_err(|_| format!("Failed read [{}]", from.display()))?, &original)?;
+        fs::remove_file(&from).map_err(|_| format!("Failed to remove [{}]", from.display()))
+    })?;
+    move_history(&from, to)?;
+    if renamed {
+        write_rss_file(config, to, &contents)?;
+    }
+    Ok(())
+}
+
+/// Stores a freshly built binary and writes the rss file. The binary is embedded in the script, or kept in the
+/// user cache with only the source written if `embed_binary` is disabled.
+fn store_binary(config: &Config, rss_file: &Path, rss: &mut RssFile, binary: Vec<u8>, fingerprint: Fingerprint) -> Result<(), String> {
+    eprintln!("Building RSS file");
+    if *config.embed_binary() {
+        rss.add_payload(Payload::new(config, rss.cargo_content(), rss.rust_content(), binary, fingerprint));
+    } else {
+        store_cached_binary(&rss.cache_key(&fingerprint), &binary)?;
+        rss.set_source_only(Payload::source_only(rss.cargo_content(), rss.rust_content(), fingerprint));
+    }
+    rss.write(config, rss_file)
+}
+
+/// Builds the script as described by `fingerprint` and stores the binary, replacing any existing binary for its
+/// target
+fn build_rss(config: &Config, rss_file: &Path, rss: &mut RssFile, fingerprint: Fingerprint) -> Result<(), Result<(), String>> {
+    let binary = build_binary(config, rss_file, rss, &fingerprint)?;
+    store_binary(config, rss_file, rss, binary, fingerprint).map_err(Err)
+}
+
+/// How to build the script for the target in `base`. Each setting is the one in `build_args`, else the one in the
+/// script's `[package.metadata.rss]`. The profile otherwise comes from the config and `RUSTFLAGS` from the environment.
+/// Other settings, and `RUSTFLAGS` if the environment has none, fall back to those its existing binary was built with,
+/// else to default features, no `RUSTFLAGS` and no extra Cargo arguments.
+fn build_fingerprint(config: &Config, base: &Fingerprint, cargo_content: &str, build_args: &BuildArgs, built: Option<&Payload>) -> Fingerprint {
+    let metadata = rss_metadata(cargo_content);
+    let string = |key: &str| metadata.get(key)?.as_str().map(str::to_string);
+    let list = |key: &str| metadata.get(key)?.as_array()?.iter().map(|v| v.as_str().map(str::to_string)).collect::<Option<Vec<_>>>();
+    let built = built.and_then(|p| p.fingerprint().as_ref());
+
+    // The config always names a profile, so the last build's is never used
+    let profile = build_args.profile.clone()
+        .or_else(|| string("profile"))
+        .unwrap_or_else(|| config.profile().clone());
+    let features = build_args.features.clone()
+        .or_else(|| list("features"))
+        .or_else(|| Some(built?.features().clone()))
+        .unwrap_or_default();
+    let no_default_features = build_args.no_default_features
+        .or_else(|| Some(!metadata.get("default-features")?.as_bool()?))
+        .or_else(|| Some(*built?.no_default_features()))
+        .unwrap_or(false);
+    let rustflags = build_args.rustflags.clone()
+        .or_else(|| string("rustflags"))
+        .or_else(|| env::var("RUSTFLAGS").ok())
+        .or_else(|| Some(built?.rustflags().clone()))
+        .unwrap_or_default();
+    let cargo_args = build_args.cargo_args.clone()
+        .or_else(|| list("cargo-args"))
+        .or_else(|| Some(built?.cargo_args().clone()))
+        .unwrap_or_default();
+
+    base.clone().with_profile(&profile).with_features(&features, no_default_features).with_flags(&rustflags, &cargo_args)
+}
+
+/// Lists why the binary for the host must be rebuilt before the script can run. Scripts without an embedded binary
+/// for the host are run from the user cache, unless binaries should be embedded and the script already has some.
+fn rebuild_reasons(config: &Config, rss: &RssFile, fingerprint: &Fingerprint) -> Vec<String> {
+    let target = fingerprint.target();
+    let mut reasons = Vec::new();
+    match rss.runnable_payload(target) {
+        Some(payload) => {
+            if *config.check_hash() {
+                // A binary for a compatible target is checked against the settings it would be built with
+                let fingerprint = match payload.target() {
+                    Some(built) => fingerprint.clone().with_target(built),
+                    None => fingerprint.clone()
+                };
+                reasons.extend(stale_reasons(rss, payload, &fingerprint));
+            }
+            if let Err(e) = payload.check_executable_format() {
+                reasons.push(e);
+            }
+        }
+        None if *config.embed_binary() && rss.has_binaries() => {
+            let mut embedded = rss.payloads().iter().filter(|p| !p.is_source_only()).filter_map(|p| p.target()).collect::<Vec<_>>();
+            embedded.dedup();
+            // Usually a script cross-compiled elsewhere, which can only run here if it can be rebuilt
+            reasons.push(format!("No embedded binary for {target} - the embedded binaries are for {}, which cannot run on this machine", embedded.join(", ")));
+        }
+        None => {}
+    }
+    reasons
+}
+
+/// Lists why `payload` no longer matches the script's source or the build described by `fingerprint`
+fn stale_reasons(rss: &RssFile, payload: &Payload, fingerprint: &Fingerprint) -> Vec<String> {
+    let mut reasons = Vec::new();
+    if !rss.is_up_to_date(payload) {
+        reasons.push("Hash changed".to_string());
+    }
+    match payload.fingerprint() {
+        Some(f) => reasons.extend(f.differences(fingerprint).into_iter().map(|d| format!("Built with {d}"))),
+        None => reasons.push("Built before build settings were recorded".to_string())
+    }
+    reasons
+}
+
+/// Lists why `rss build` must rebuild the script for the target in `fingerprint`
+fn build_reasons(config: &Config, rss: &RssFile, fingerprint: &Fingerprint) -> Vec<String> {
+    let target = fingerprint.target();
+    let Some(payload) = rss.payload(target) else {
+        return vec![format!("No binary for {target}")];
+    };
+
+    let mut reasons = stale_reasons(rss, payload, fingerprint);
+    if *config.embed_binary() {
+        if payload.is_source_only() {
+            reasons.push(format!("No embedded binary for {target}"));
+        }
+        else if target == env!("RSS_HOST_TARGET") {
+            reasons.extend(payload.check_executable_format().err());
+        }
+    }
+    else if !payload.is_source_only() {
+        reasons.push("Binary is embedded but should be kept in the user cache".to_string());
+    }
+    reasons
+}
+
+/// Adds `path` to `files` if it is a file, or every rss file below it if it is a directory.
+/// Hidden directories and Cargo `target` directories are skipped.
+fn collect_rss_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries = fs::read_dir(path).map_err(|_| format!("Failed read directory [{}]", path.display()))?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>().map_err(|_| format!("Failed read directory [{}]", path.display()))?;
+    entries.sort();
+
+    for entry in entries {
+        if entry.is_dir() {
+            let name = entry.file_name().unwrap().to_string_lossy();
+            if !name.starts_with('.') && name != "target" {
+                collect_rss_files(&entry, files)?;
+            }
+        }
+        else if entry.extension().is_some_and(|e| e == "rss") {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Rebuilds a script for the target in `fingerprint` if it is stale or `force` is set
+fn build_file(config: &Config, rss_file: &Path, fingerprint: &Fingerprint, build_args: &BuildArgs, force: bool) -> Result<(), String> {
+    check_file(rss_file)?;
+    let _lock = CacheLock::script(rss_file, || eprintln!("[{}] Waiting for another run to finish building", rss_file.display()))?;
+    let mut rss = RssFile::read(rss_file)?;
+    let fingerprint = &build_fingerprint(config, fingerprint, rss.cargo_content(), build_args, rss.payload(fingerprint.target()));
+
+    let reasons = build_reasons(config, &rss, fingerprint);
+    if reasons.is_empty() && !force {
+        println!("[{}] Up to date", rss_file.display());
+        return Ok(());
+    }
+    for reason in &reasons {
+        println!("[{}] {reason}", rss_file.display());
+    }
+
+    match build_rss(config, rss_file, &mut rss, fingerprint.clone()) {
+        Ok(()) => Ok(()),
+        Err(Ok(())) if fingerprint.target() != env!("RSS_HOST_TARGET") => Err(format!(
+            "Cargo build failed for [{}] - cross-compiling needs the target installed (rustup target add {}) and may need a linker for it",
+            rss_file.display(), fingerprint.target()
+        )),
+        Err(Ok(())) => Err(format!("Cargo build failed for [{}]", rss_file.display())),
+        Err(Err(e)) => Err(e)
+    }
+}
+
+/// The fingerprint of a build on this machine, asking rustc in the directory that builds run in
+fn current_fingerprint(config: &Config) -> Fingerprint {
+    let directory = workspace_directory(config).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+    Fingerprint::current(&directory)
+}
+
+/// Takes the lock for rebuilding and rewriting the rss file, waiting for any other run holding it
+fn wait_for_lock(rss_file: &Path) -> CacheLock {
+    CacheLock::script(rss_file, || eprintln!("Waiting for another run to finish building"))
+        .unwrap_or_else(|e| print_err_exit(Some(&e), false))
+}
+
 fn main() {
-    let mut args = env::args().collect::<Vec<_>>().into_iter();
+    let mut args = env::args_os().collect::<Vec<_>>().into_iter();
 
     let _self_location = PathBuf::from(args.next().unwrap()).parent().unwrap().to_owned();
 
     let command = args.next().unwrap_or_else(|| print_err_exit(None, true));
 
-    let config = Config::read(&env::current_exe().unwrap().parent().unwrap()).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+    let config = Config::read(env::current_exe().unwrap().parent().unwrap()).unwrap_or_else(|e| print_err_exit(Some(&e), false));
 
-    match command.as_str() {
+    match command.to_string_lossy().as_ref() {
         "help" | "h" => {
             println!("{HELP_TEXT}");
         }
         "edit" | "e" => {
+            forward_signals();
+            let build_args = BuildArgs::take(&mut args).unwrap_or_else(|e| print_err_exit(Some(&e), false));
             let rss_file = get_file(&mut args, true).unwrap_or_else(|e| print_err_exit(Some(&e), false));
             check_file(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
 
-            let (cargo_content, rust_content) = get_cargo_and_source_rss(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let mut rss = RssFile::read(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
 
-            println!("Generating project files");
-            generate_project(&config, &rss_file, &cargo_content, &rust_content).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let workspace = create_workspace(&config, &rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            eprintln!("Generating project files");
+            generate_project(&workspace, rss.cargo_content(), rss.rust_content()).unwrap_or_else(|e| print_err_exit(Some(&e), false));
 
-            println!("Starting editor");
-            start_editor_blocking(&config, &rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            eprintln!("Starting editor");
+            start_editor_blocking(&config, &workspace).unwrap_or_else(|e| print_err_exit(Some(&e), false));
 
+            let host = current_fingerprint(&config);
             let binary;
+            let fingerprint;
             loop {
-                println!("Building project");
-                match build_project(&config, &rss_file) {
+                // The build settings may have been changed in the manifest
+                let (cargo_content, _) = get_cargo_and_source_project(&workspace).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                let attempt = build_fingerprint(&config, &host, &cargo_content, &build_args, rss.payload(host.target()));
+
+                eprintln!("Building project");
+                let built = choose_binary(&workspace, &rss_file).map_err(Err)
+                    .and_then(|_| build_project(&workspace, &rss_file, &attempt, None));
+                match built {
                     Ok(b) => {
                         binary = b;
+                        fingerprint = attempt;
                         break;
                     },
                     Err(Ok(_)) => {}
                     Err(Err(e)) => print_err_exit(Some(&e), false)
                 };
 
-                println!("Failed Cargo build, reopening editor");
-                start_editor_blocking(&config, &rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                if is_interrupted() {
+                    eprintln!("Build interrupted, keeping the edited project in {}", workspace.display());
+                    exit_if_interrupted();
+                }
+                eprintln!("Failed Cargo build, reopening editor");
+                start_editor_blocking(&config, &workspace).unwrap_or_else(|e| print_err_exit(Some(&e), false));
             }
 
 
-            let (cargo_content, rust_content) = get_cargo_and_source_project(&config, &rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let (cargo_content, rust_content) = get_cargo_and_source_project(&workspace).unwrap_or_else(|e| print_err_exit(Some(&e), false));
 
-            println!("Building RSS file");
-            build_rss(&config, &rss_file, &cargo_content, &rust_content, &binary).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let _lock = wait_for_lock(&rss_file);
+            // Keep binaries added by other runs while the editor was open
+            rss = RssFile::read(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let removed = rss.set_source(cargo_content, rust_content);
+            if removed != 0 {
+                eprintln!("Removing {removed} binaries built from the previous version");
+            }
 
-            println!("Cleaning project files");
-            delete_project(&config, &rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            store_binary(&config, &rss_file, &mut rss, binary, fingerprint).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+
+            eprintln!("Cleaning project files");
+            delete_project(&workspace).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+        }
+        "build" | "b" => {
+            forward_signals();
+            let mut target = None;
+            let mut build_args = BuildArgs::default();
+            let mut force = false;
+            let mut check = false;
+            let mut jobs = 1;
+            let mut paths = Vec::new();
+            while let Some(arg) = args.next() {
+                if build_args.parse(&arg, &mut args).unwrap_or_else(|e| print_err_exit(Some(&e), false)) {
+                    continue;
+                }
+                match arg.to_string_lossy().as_ref() {
+                    "--target" => target = Some(args.next().and_then(|t| t.into_string().ok())
+                        .unwrap_or_else(|| print_err_exit(Some("--target requires a target triple"), false))),
+                    "--force" => force = true,
+                    "--check" => check = true,
+                    "-j" | "--jobs" => jobs = args.next().and_then(|j| j.to_str()?.parse().ok()).filter(|j| *j > 0)
+                        .unwrap_or_else(|| print_err_exit(Some("--jobs requires a number of jobs"), false)),
+                    _ => paths.push(path::absolute(PathBuf::from(arg)).unwrap())
+                }
+            }
+            if paths.is_empty() {
+                print_err_exit(Some("This command requires a file argument that has not been provided"), false);
+            }
+
+            let mut files = Vec::new();
+            for path in &paths {
+                collect_rss_files(path, &mut files).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            }
+            let fingerprint = match &target {
+                Some(target) => current_fingerprint(&config).with_target(target),
+                None => current_fingerprint(&config)
+            };
+            // Only binaries for the host are looked up in the user cache, and keeping a binary there replaces the
+            // embedded ones, so binaries for other targets must be embedded
+            if !*config.embed_binary() && fingerprint.target() != env!("RSS_HOST_TARGET") {
+                print_err_exit(Some(&format!("Cannot build for {} while binaries are kept in the user cache - enable storing compiled code in the script in the config", fingerprint.target())), false);
+            }
+
+            if check {
+                let mut stale = 0;
+                for rss_file in &files {
+                    check_file(rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                    let rss = RssFile::read(rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                    let fingerprint = build_fingerprint(&config, &fingerprint, rss.cargo_content(), &build_args, rss.payload(fingerprint.target()));
+                    let reasons = build_reasons(&config, &rss, &fingerprint);
+                    if !reasons.is_empty() {
+                        stale += 1;
+                        println!("[{}] Stale: {}", rss_file.display(), reasons.join(", "));
+                    }
+                }
+                if stale != 0 {
+                    print_err_exit(Some(&format!("{stale} of {} scripts need rebuilding", files.len())), false);
+                }
+                println!("All {} scripts are up to date", files.len());
+                return;
+            }
+
+            let count = files.len();
+            let queue = Mutex::new(files.into_iter());
+            let failures = Mutex::new(Vec::new());
+            thread::scope(|scope| {
+                for _ in 0..jobs {
+                    scope.spawn(|| loop {
+                        let Some(rss_file) = queue.lock().unwrap().next().filter(|_| !is_interrupted()) else { break };
+                        if let Err(e) = build_file(&config, &rss_file, &fingerprint, &build_args, force) {
+                            eprintln!("{e}");
+                            failures.lock().unwrap().push(rss_file);
+                        }
+                    });
+                }
+            });
+            exit_if_interrupted();
+
+            let failures = failures.into_inner().unwrap();
+            if !failures.is_empty() {
+                let list = failures.iter().map(|f| format!("    {}", f.display())).collect::<Vec<_>>().join("\n");
+                print_err_exit(Some(&format!("Failed to build {} of {count} scripts:\n{list}", failures.len())), false);
+            }
+        }
+        "check-format" => {
+            let rss_file = get_file(&mut args, false).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            check_file(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+
+            let contents = fs::read(&rss_file).unwrap_or_else(|_| print_err_exit(Some(&format!("Failed read [{}]", rss_file.display())), false));
+            if contents.is_empty() {
+                println!("Empty file - will be created from the default template on edit");
+                return;
+            }
+
+            match RssFile::parse(&contents) {
+                Ok(rss) => {
+                    println!("Valid rss file");
+                    println!("Manifest: {} bytes", rss.cargo_content().len());
+                    println!("Source: {} lines", rss.rust_content().lines().count());
+                    for payload in rss.payloads() {
+                        if payload.is_source_only() {
+                            println!("Source only for {}: binary kept in the user cache", payload.target().unwrap_or("unknown target"));
+                        } else {
+                            println!("Payload for {}: {} bytes ({}{})", payload.target().unwrap_or("unknown target"), payload.binary().map_or(0, <[u8]>::len), match payload.encoding() {
+                                PayloadEncoding::Base64 => "base64",
+                                PayloadEncoding::Raw => "raw"
+                            }, match payload.compression() {
+                                Compression::None => "",
+                                Compression::Deflate(_) => ", deflate"
+                            });
+                        }
+                        println!("    Hash: {}", payload.hash());
+                        if let Some(fingerprint) = payload.fingerprint() {
+                            println!("    Fingerprint: {fingerprint}");
+                        }
+                    }
+                }
+                Err(e) => {
+                    let (line, column) = line_column(&contents, e.offset());
+                    print_err_exit(Some(&format!("{}:{line}:{column}: {e}", rss_file.display())), false);
+                }
+            }
+        }
+        "mv" | "cp" => {
+            let from = get_file(&mut args, false).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            check_file(&from).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let to = get_file(&mut args, false).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            // Like mv and cp, a directory destination keeps the file name
+            let to = if to.is_dir() { to.join(from.file_name().unwrap()) } else { to };
+
+            copy_script(&config, &from, &to, command == "cp").unwrap_or_else(|e| print_err_exit(Some(&e), false));
+        }
+        "history" => {
+            let rss_file = get_file(&mut args, false).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            check_file(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+
+            let versions = versions(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            if versions.is_empty() {
+                println!("No history for [{}]", rss_file.display());
+                return;
+            }
+
+            let current = source_text(&fs::read(&rss_file).unwrap_or_else(|_| print_err_exit(Some(&format!("Failed read [{}]", rss_file.display())), false)));
+            println!("Versions of [{}], newest first, with the lines added and removed since:", rss_file.display());
+            for (i, version) in versions.iter().enumerate() {
+                let contents = fs::read(&version.path).unwrap_or_else(|_| print_err_exit(Some(&format!("Failed read [{}]", version.path.display())), false));
+                let (added, removed) = diff_stats(&source_text(&contents), &current);
+                println!("{:>4}  {}  +{added} -{removed}", i + 1, format_time(version.time));
+            }
+        }
+        "rollback" => {
+            let rss_file = get_file(&mut args, false).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            check_file(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let n = match args.next() {
+                None => 1,
+                Some(n) => n.to_str().and_then(|n| n.parse::<usize>().ok()).filter(|n| *n > 0)
+                    .unwrap_or_else(|| print_err_exit(Some("Version must be a number listed by rss history"), false))
+            };
+
+            let _lock = wait_for_lock(&rss_file);
+            let versions = versions(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let Some(version) = versions.get(n - 1) else {
+                print_err_exit(Some(&format!("No version {n} in the history of [{}]", rss_file.display())), false);
+            };
+            let contents = fs::read(&version.path).unwrap_or_else(|_| print_err_exit(Some(&format!("Failed read [{}]", version.path.display())), false));
+
+            write_rss_file(&config, &rss_file, &contents).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            println!("Restored the version replaced at {}", format_time(version.time));
+        }
+        "cache" => {
+            match args.next().as_ref().map(|a| a.to_string_lossy()).as_deref() {
+                None => {
+                    let usage = cache_usage().unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                    println!("Cache directory: {}", cache_directory().unwrap_or_else(|e| print_err_exit(Some(&e), false)).display());
+                    println!("    Binaries: {} ({} files)", format_size(usage.binaries.0), usage.binaries.1);
+                    println!("    Build artifacts: {}", format_size(usage.build_artifacts));
+                    println!("    History: {}", format_size(usage.history));
+                    println!("    Workspaces and temporary files: {}", format_size(usage.temporary));
+                    println!("Total: {}", format_size(usage.binaries.0 + usage.build_artifacts + usage.history + usage.temporary));
+                }
+                Some("prune") => {
+                    let all = match args.next().as_ref().map(|a| a.to_string_lossy()).as_deref() {
+                        None => false,
+                        Some("--all") => true,
+                        Some(a) => print_err_exit(Some(&format!("Unknown argument [{a}]")), true)
+                    };
+                    let freed = prune_cache(all).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                    println!("Freed {}", format_size(freed));
+                }
+                Some(a) => print_err_exit(Some(&format!("Unknown cache command [{a}]")), true)
+            }
         }
         "config" | "c" => {
-            println!("Config file location: {}", Config::location(&env::current_exe().unwrap().parent().unwrap()).display());
+            println!("Config file location: {}", Config::location(env::current_exe().unwrap().parent().unwrap()).display());
         }
         path => {
+            let mut build_args = BuildArgs::default();
             let rss_file = if path == "r" || path == "run" {
+                build_args = BuildArgs::take(&mut args).unwrap_or_else(|e| print_err_exit(Some(&e), false));
                 get_file(&mut args, false).unwrap_or_else(|e| print_err_exit(Some(&e), false))
             } else {
-                path::absolute(PathBuf::from(path)).unwrap()
+                path::absolute(PathBuf::from(&command)).unwrap()
             };
+            let script_args = get_script_args(args);
             check_file(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            forward_signals();
 
-            let (mut binary, hash) = get_binary_rss(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let mut rss = RssFile::read(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
 
-            if *config.check_hash() {
-                let (cargo_content, rust_content) = get_cargo_and_source_rss(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
-                let mut h = DefaultHasher::new();
-                cargo_content.hash(&mut h);
-                rust_content.hash(&mut h);
-                #[cfg(target_os = "windows")]
-                "windows".hash(&mut h);
-                #[cfg(target_os = "linux")]
-                "linux".hash(&mut h);
-
-                if hash != h.finish() {
-                    println!("Hash changed, rebuilding project");
-                    println!("Generating project files");
-                    generate_project(&config, &rss_file, &cargo_content, &rust_content).unwrap_or_else(|e| print_err_exit(Some(&e), false));
-                    println!("Building project");
-                    binary = match build_project(&config, &rss_file) {
-                        Ok(b) => b,
-                        Err(Ok(_)) => print_err_exit(Some("Cargo build failed"), false),
-                        Err(Err(e)) => print_err_exit(Some(&e), false)
-                    };
+            if *config.check_hash() && rss.upgrade_hashes() {
+                let _lock = wait_for_lock(&rss_file);
+                rss = RssFile::read(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                if rss.upgrade_hashes() {
+                    eprintln!("Upgrading hash to SHA-256");
+                    // Only saves rehashing the legacy hash next time, so a script that can't be written still runs
+                    if let Err(e) = rss.write(&config, &rss_file) {
+                        eprintln!("{e}\nKeeping the legacy hash");
+                    }
+                }
+            }
 
-                    let (cargo_content, rust_content) = get_cargo_and_source_project(&config, &rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            // Stale checks compare the toolchain, so rustc is only asked for its version when they are on or a build
+            // may be needed
+            let probe = |rss: &RssFile| {
+                let host = current_fingerprint(&config);
+                build_fingerprint(&config, &host, rss.cargo_content(), &build_args, rss.payload(host.target()))
+            };
+            let fingerprint = match config.check_hash() {
+                true => probe(&rss),
+                false => build_fingerprint(&config, &Fingerprint::unprobed(), rss.cargo_content(), &build_args, rss.payload(env!("RSS_HOST_TARGET")))
+            };
+            let target = fingerprint.target().clone();
+            let mut exe_file = None;
+            let reasons = rebuild_reasons(&config, &rss, &fingerprint);
+            if !reasons.is_empty() {
+                let _lock = wait_for_lock(&rss_file);
+                // Another run may have rebuilt the script while this one was waiting
+                rss = RssFile::read(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                let reasons = rebuild_reasons(&config, &rss, &fingerprint);
+                if !reasons.is_empty() {
+                    let fingerprint = probe(&rss);
+                    // Left by an earlier run that could not rewrite the script
+                    exe_file = get_cached_binary(&rss.cache_key(&fingerprint)).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                    if exe_file.is_none() {
+                        for reason in &reasons {
+                            eprintln!("{reason}");
+                        }
+                        eprintln!("Rebuilding project");
+                        let binary = match build_binary(&config, &rss_file, &mut rss, &fingerprint) {
+                            Ok(binary) => binary,
+                            Err(Ok(())) => print_err_exit(Some("Cargo build failed"), false),
+                            Err(Err(e)) => print_err_exit(Some(&format!("{e}\nCannot run without rebuilding")), false)
+                        };
+                        check_executable_format(&binary).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                        let key = rss.cache_key(&fingerprint);
+                        match store_binary(&config, &rss_file, &mut rss, binary.clone(), fingerprint) {
+                            Ok(()) => eprintln!("Proceeding with running"),
+                            Err(e) => {
+                                // Such as a script on a read-only mount, which can still run what was just built
+                                eprintln!("{e}\nRunning the rebuilt binary from the user cache instead");
+                                exe_file = Some(store_cached_binary(&key, &binary).unwrap_or_else(|e| print_err_exit(Some(&e), false)));
+                            }
+                        }
+                    }
+                }
+            }
 
-                    println!("Building RSS file");
-                    build_rss(&config, &rss_file, &cargo_content, &rust_content, &binary).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let exe_file = match (exe_file, rss.runnable_payload(&target)) {
+                (Some(exe_file), _) => exe_file,
+                (None, Some(payload)) => {
+                    payload.check_executable_format().unwrap_or_else(|e| print_err_exit(Some(&e), false));
 
-                    println!("Cleaning project files");
-                    delete_project(&config, &rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                    #[cfg(target_os = "linux")]
+                    if *config.execution() == ExecutionStrategy::Memory {
+                        let binary = payload.binary().unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                        if let Ok(status) = execute_binary_in_memory(&rss_file, binary, &script_args) {
+                            exit_with_status(status);
+                        }
+                    }
 
-                    println!("Proceeding with running");
+                    extract_binary(payload).unwrap_or_else(|e| print_err_exit(Some(&e), false))
                 }
-            }
-
-            write_binary(&rss_file, &binary).unwrap_or_else(|e| print_err_exit(Some(&e), false));
-            drop(binary);
+                (None, None) => {
+                    let fingerprint = match config.check_hash() {
+                        true => fingerprint,
+                        false => probe(&rss)
+                    };
+                    let key = rss.cache_key(&fingerprint);
+                    match get_cached_binary(&key).unwrap_or_else(|e| print_err_exit(Some(&e), false)) {
+                        Some(exe_file) => exe_file,
+                        None => {
+                            let _lock = wait_for_lock(&rss_file);
+                            // Another run may have built the script while this one was waiting
+                            match get_cached_binary(&key).unwrap_or_else(|e| print_err_exit(Some(&e), false)) {
+                                Some(exe_file) => exe_file,
+                                None => {
+                                    eprintln!("Building into the user cache");
+                                    // Written back if a binary is chosen while building, so read under the lock
+                                    rss = RssFile::read(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                                    let cargo_content = rss.cargo_content().clone();
+                                    let binary = match build_binary(&config, &rss_file, &mut rss, &fingerprint) {
+                                        Ok(binary) => binary,
+                                        Err(Ok(())) => print_err_exit(Some("Cargo build failed"), false),
+                                        Err(Err(e)) => print_err_exit(Some(&format!("{e}\nCannot run without building")), false)
+                                    };
+                                    check_executable_format(&binary).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                                    let key = rss.cache_key(&fingerprint);
+                                    if *rss.cargo_content() != cargo_content {
+                                        rss.write(&config, &rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+                                    }
+                                    store_cached_binary(&key, &binary).unwrap_or_else(|e| print_err_exit(Some(&e), false))
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            drop(rss);
+            exit_if_interrupted();
 
-            execute_binary(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
-            delete_binary(&rss_file).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            let status = execute_binary(&exe_file, &script_args).unwrap_or_else(|e| print_err_exit(Some(&e), false));
+            exit_with_status(status);
         }
 
     }
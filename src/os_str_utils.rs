@@ -7,12 +7,12 @@ pub trait Append<Segment : ?Sized> : Sized
         Segment : ToOwned<Owned = Self>,
         Self : Borrow<Segment>,
 {
-    fn append (self: Self, s: impl AsRef<Segment>) -> Self;
+    fn append (self, s: impl AsRef<Segment>) -> Self;
 }
 
 #[allow(dead_code)]
 impl Append<OsStr> for OsString {
-    fn append (mut self: OsString, s: impl AsRef<OsStr>)
+    fn append (mut self, s: impl AsRef<OsStr>)
                -> Self
     {
         self.push(s);
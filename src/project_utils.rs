@@ -1,80 +1,338 @@
-use std::ffi::OsStr;
+use std::env;
 use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::io::{self, BufRead, BufReader, ErrorKind, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use serde_json::Value;
+use crate::cache_utils::{cache_directory, target_directory, CacheLock};
 use crate::config::Config;
-#[cfg(target_os = "windows")]
-use crate::os_str_utils::Append;
+use crate::diagnostic_utils::SourceMap;
+use crate::fingerprint::Fingerprint;
+use crate::rss_utils::{add_rss_metadata, package_name, rss_metadata};
+use crate::signal_utils::{run_forwarding_signals_with, Forward};
 
-pub fn generate_project(config: &Config, rss_file: &Path, cargo_content: &str, rust_content: &str) -> Result<(), String> {
-    let mut directory = rss_file.parent().unwrap().to_path_buf();
-    let file_name = rss_file.file_stem().unwrap();
-    if *config.new_dir() {
-        directory = directory.join(file_name);
-        fs::create_dir(&directory).map_err(|_| "Failed to create base directory".to_string())?;
+/// Where build workspaces are created
+pub enum WorkspaceLocation {
+    /// The per-user cache directory
+    Cache,
+    /// The system temporary directory
+    Temp
+}
+
+impl WorkspaceLocation {
+    pub fn from_str(s: &str) -> Result<WorkspaceLocation, String> {
+        Ok(match s {
+            "cache" => WorkspaceLocation::Cache,
+            "temp" => WorkspaceLocation::Temp,
+            l => return Err(format!("Workspace location [{l}] in config file not supported"))
+        })
+    }
+}
+
+/// The directory workspaces are created in, which is created if needed
+pub fn workspace_directory(config: &Config) -> Result<PathBuf, String> {
+    let base = match config.workspace() {
+        WorkspaceLocation::Cache => cache_directory()?,
+        WorkspaceLocation::Temp => temp_directory()?
+    }.join("workspaces");
+    fs::create_dir_all(&base).map_err(|_| format!("Failed to create workspace directory {}", base.display()))?;
+    Ok(base)
+}
+
+/// This user's directory for rss in the system temporary directory, which is created if needed. Other users can
+/// write to the system temporary directory, so a directory they could have created or replaced is refused.
+#[cfg(target_os = "linux")]
+fn temp_directory() -> Result<PathBuf, String> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    let uid = unsafe { libc::getuid() };
+    let directory = env::temp_dir().join(format!("rss-{uid}"));
+    match fs::DirBuilder::new().mode(0o700).create(&directory) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(_) => return Err(format!("Failed to create temporary directory {}", directory.display()))
     }
 
-    let src = directory.join("src");
+    let metadata = fs::symlink_metadata(&directory)
+        .map_err(|_| format!("Failed to read temporary directory {}", directory.display()))?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.permissions().mode() & 0o077 != 0 {
+        return Err(format!("Temporary directory {} is not a private directory owned by this user - refusing to build in it", directory.display()));
+    }
+    Ok(directory)
+}
+
+/// Elsewhere the system temporary directory is already private to the user
+#[cfg(not(target_os = "linux"))]
+fn temp_directory() -> Result<PathBuf, String> {
+    Ok(env::temp_dir().join("rss"))
+}
+
+/// Creates a new, empty workspace to generate and build the project for `rss_file` in, returning its path.
+/// Projects are never generated beside the script so that existing files can't be overwritten or deleted, and the
+/// workspace must not already exist so that cleaning it up only removes what rss created.
+pub fn create_workspace(config: &Config, rss_file: &Path) -> Result<PathBuf, String> {
+    static WORKSPACES: AtomicUsize = AtomicUsize::new(0);
+
+    let base = workspace_directory(config)?;
+
+    let mut name = rss_file.file_stem().unwrap().to_os_string();
+    name.push(format!("-{}-{}", process::id(), WORKSPACES.fetch_add(1, Ordering::SeqCst)));
+    let workspace = base.join(name);
+    create_new_workspace(&workspace)?;
+    Ok(workspace)
+}
+
+/// Creates the workspace directory, which only the user can access, failing if it already exists
+fn create_new_workspace(workspace: &Path) -> Result<(), String> {
+    match fs::create_dir(workspace) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists =>
+            return Err(format!("Workspace {} already exists - refusing to build somewhere that may hold other files", workspace.display())),
+        Err(_) => return Err(format!("Failed to create workspace {}", workspace.display()))
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(workspace, fs::Permissions::from_mode(0o700))
+            .map_err(|_| format!("Failed to make workspace {} private", workspace.display()))?;
+    }
+
+    Ok(())
+}
+
+pub fn generate_project(workspace: &Path, cargo_content: &str, rust_content: &str) -> Result<(), String> {
+    let src = workspace.join("src");
     fs::create_dir(&src).map_err(|_| "Failed to create src directory".to_string())?;
 
     let main_file = src.join("main.rs");
     fs::write(&main_file, rust_content.as_bytes()).map_err(|_| "Failed to create main.rs".to_string())?;
 
-    let cargo_file = directory.join("Cargo.toml");
+    let cargo_file = workspace.join("Cargo.toml");
     fs::write(&cargo_file, cargo_content.as_bytes()).map_err(|_| "Failed to create Cargo.toml".to_string())?;
 
     Ok(())
 }
 
-pub fn build_project(config: &Config, rss_file: &Path) -> Result<Vec<u8>, Result<(), String>> {
-    let mut directory = rss_file.parent().unwrap().to_path_buf();
-    let file_name = rss_file.file_stem().unwrap();
-    if *config.new_dir() {
-        directory = directory.join(file_name);
+/// Builds the project in `workspace` with the target and settings in `fingerprint`, and returns the binary.
+/// Builds share a target directory in the cache so that dependencies are reused across rebuilds and scripts.
+/// Diagnostics are mapped onto the rss file with `source_map`, or left pointing into the workspace if `None`.
+pub fn build_project(workspace: &Path, rss_file: &Path, fingerprint: &Fingerprint, source_map: Option<&SourceMap>) -> Result<Vec<u8>, Result<(), String>> {
+    // The generated project has no lockfile to check against, so Cargo would always refuse to build
+    if let Some(arg) = fingerprint.cargo_args().iter().find(|a| *a == "--locked" || *a == "--frozen") {
+        return Err(Err(format!("{arg} can't be used as rss files don't store a Cargo.lock")));
     }
+    let target_directory = target_directory().map_err(Err)?;
+    let cargo_content = fs::read_to_string(workspace.join("Cargo.toml")).map_err(|_| Err("Failed read Cargo.toml".to_string()))?;
+    let package = package_name(&cargo_content).unwrap_or_default();
+    let _lock = CacheLock::build(package, || eprintln!("Waiting for another build of {package} to finish")).map_err(Err)?;
 
-    if !Command::new("cargo").args([OsStr::new("build"), OsStr::new("-r")]).current_dir(&directory).status()
-        .map_err(|e| {println!("{:?}", e); Err("Failed to run Cargo".to_string())})?.success() {
+    let mut command = Command::new("cargo");
+    command.args(["build", "--message-format=json", "--profile", fingerprint.profile(), "--target", fingerprint.target()])
+        .current_dir(workspace)
+        .env("CARGO_TARGET_DIR", &target_directory)
+        // Only the flags recorded in the fingerprint are passed on, leaving rustflags in Cargo's config to apply
+        // when there are none
+        .env_remove("RUSTFLAGS")
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if !fingerprint.rustflags().is_empty() {
+        command.env("RUSTFLAGS", fingerprint.rustflags());
+    }
+    if !fingerprint.features().is_empty() {
+        command.args(["--features", &fingerprint.features().join(",")]);
+    }
+    if *fingerprint.no_default_features() {
+        command.arg("--no-default-features");
+    }
+    if let Some(bin) = rss_metadata(&cargo_content).get("bin").and_then(|b| b.as_str()) {
+        command.args(["--bin", bin]);
+    }
+    command.args(fingerprint.cargo_args());
+    let mut artifacts = Vec::new();
+    let status = run_forwarding_signals_with(&mut command, Forward::ProcessGroup, |child| {
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        thread::scope(|s| {
+            // Cargo's own progress and errors
+            s.spawn(|| for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                match source_map {
+                    Some(source_map) => eprintln!("{}", source_map.map_cargo_line(&line, workspace)),
+                    None => eprintln!("{line}")
+                }
+            });
+
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if let Some(artifact) = handle_cargo_message(&line, source_map) {
+                    artifacts.push(artifact);
+                }
+            }
+        });
+    }).map_err(|e| {eprintln!("{:?}", e); Err("Failed to run Cargo".to_string())})?;
+    if !status.success() {
         return Err(Ok(()))
     }
 
-    #[cfg(target_os = "windows")]
-    return Ok(fs::read(directory.join("target").join("release")
-        .join(file_name.to_os_string().append(OsStr::new(".exe")))
-    ).map_err(|_| Err("Failed read built binary".to_string()))?);
+    // Taken from Cargo rather than the file name, since the package or its binaries may be named differently
+    let executable = match artifacts.len() {
+        0 => return Err(Err("Cargo did not build a binary".to_string())),
+        1 => artifacts.remove(0),
+        _ => return Err(Err(format!("{} builds several binaries - choose one with bin = \"<name>\" under [package.metadata.rss]", rss_file.display())))
+    };
+    fs::read(&executable).map_err(|_| Err(format!("Failed read built binary {}", executable.display())))
+}
 
-    #[cfg(target_os = "linux")]
-    return Ok(fs::read(directory.join("target").join("release")
-        .join(file_name)
-    ).map_err(|_| Err("Failed read built binary".to_string()))?);
+/// Makes sure the project in `workspace` builds a single binary. If the manifest declares several and doesn't name one
+/// with `bin` under `[package.metadata.rss]`, the user chooses one and the choice is recorded there. This is asked
+/// before building so that no other build waits on the answer. Returns the updated manifest if a choice was recorded.
+pub fn choose_binary(workspace: &Path, rss_file: &Path) -> Result<Option<String>, String> {
+    // Builds running at once with -j take turns to ask
+    static PROMPT: Mutex<()> = Mutex::new(());
+
+    let cargo_file = workspace.join("Cargo.toml");
+    let cargo_content = fs::read_to_string(&cargo_file).map_err(|_| "Failed read Cargo.toml".to_string())?;
+    if rss_metadata(&cargo_content).contains_key("bin") {
+        return Ok(None);
+    }
+    let mut names = binary_names(workspace);
+    if names.len() < 2 {
+        return Ok(None);
+    }
+    names.sort_unstable();
+
+    if !io::stdin().is_terminal() {
+        return Err(format!("{} builds several binaries ({}) - run it from a terminal to choose one, or choose one with bin = \"<name>\" under [package.metadata.rss]",
+            rss_file.display(), names.join(", ")));
+    }
+
+    let _prompt = PROMPT.lock().unwrap_or_else(|e| e.into_inner());
+    eprintln!("{} builds several binaries:", rss_file.display());
+    for (i, name) in names.iter().enumerate() {
+        eprintln!("  {}) {name}", i + 1);
+    }
+    let name = loop {
+        eprint!("Choose one [1-{}]: ", names.len());
+        io::stderr().flush().map_err(|_| "Failed to write to stderr".to_string())?;
+
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice).map_err(|_| "Failed to read choice".to_string())? == 0 {
+            return Err(format!("No binary chosen from {}", names.join(", ")));
+        }
+        match choice.trim().parse::<usize>() {
+            Ok(i) if (1..=names.len()).contains(&i) => break names.swap_remove(i - 1),
+            _ => eprintln!("Not a choice: {}", choice.trim())
+        }
+    };
+
+    let cargo_content = add_rss_metadata(&cargo_content, "bin", &name)
+        .ok_or_else(|| format!("Failed to record the choice - add bin = \"{name}\" to [package.metadata.rss] of {}", rss_file.display()))?;
+    fs::write(&cargo_file, &cargo_content).map_err(|_| format!("Failed to write {}", cargo_file.display()))?;
+    eprintln!("Recorded the choice as bin = \"{name}\" under [package.metadata.rss]");
+    Ok(Some(cargo_content))
 }
 
-pub fn get_cargo_and_source_project(config: &Config, rss_file: &Path) -> Result<(String, String), String> {
-    let mut directory = rss_file.parent().unwrap().to_path_buf();
-    let file_name = rss_file.file_stem().unwrap();
-    if *config.new_dir() {
-        directory = directory.join(file_name);
+/// Names of the binaries the project in `workspace` declares, or none if Cargo can't read its manifest, in which case
+/// the build reports why
+fn binary_names(workspace: &Path) -> Vec<String> {
+    let Ok(output) = Command::new("cargo").args(["metadata", "--no-deps", "--format-version", "1"]).current_dir(workspace).output() else {
+        return Vec::new();
+    };
+    let Ok(metadata) = serde_json::from_slice::<Value>(&output.stdout) else {
+        return Vec::new();
+    };
+    metadata["packages"].as_array().into_iter().flatten()
+        .flat_map(|p| p["targets"].as_array().into_iter().flatten())
+        .filter(|t| t["kind"].as_array().is_some_and(|k| k.iter().any(|k| k == "bin")))
+        .filter_map(|t| Some(t["name"].as_str()?.to_string()))
+        .collect()
+}
+
+/// Handles a JSON message from Cargo, printing diagnostics the way rustc renders them and returning built binaries.
+/// Lines that aren't JSON, such as output from build scripts, are printed as they are.
+fn handle_cargo_message(line: &str, source_map: Option<&SourceMap>) -> Option<PathBuf> {
+    let Ok(message) = serde_json::from_str::<Value>(line) else {
+        eprintln!("{line}");
+        return None;
+    };
+
+    match message["reason"].as_str()? {
+        "compiler-message" => {
+            let rendered = message["message"]["rendered"].as_str()?;
+            match source_map {
+                Some(source_map) => eprint!("{}", source_map.map_rendered(rendered)),
+                None => eprint!("{rendered}")
+            }
+            None
+        }
+        // Build scripts are executables too, so only binary targets are kept
+        "compiler-artifact" if message["target"]["kind"].as_array()?.iter().any(|k| k == "bin") =>
+            Some(PathBuf::from(message["executable"].as_str()?)),
+        _ => None
     }
-    let main_file = directory.join("src").join("main.rs");
+}
+
+pub fn get_cargo_and_source_project(workspace: &Path) -> Result<(String, String), String> {
+    let main_file = workspace.join("src").join("main.rs");
     let rust_content = fs::read_to_string(&main_file).map_err(|_| "Failed read src/main.rs".to_string())?;
-    let cargo_file = directory.join("Cargo.toml");
+    let cargo_file = workspace.join("Cargo.toml");
     let cargo_content = fs::read_to_string(&cargo_file).map_err(|_| "Failed read Cargo.toml".to_string())?;
 
     Ok((cargo_content, rust_content))
 }
 
-pub fn delete_project(config: &Config, rss_file: &Path) -> Result<(), String> {
-    let mut directory = rss_file.parent().unwrap().to_path_buf();
-    let file_name = rss_file.file_stem().unwrap();
-    if *config.new_dir() {
-        directory = directory.join(file_name);
-        fs::remove_dir_all(directory).map_err(|_| "Failed delete project".to_string())?;
+/// Deletes a workspace made by `create_workspace`
+pub fn delete_project(workspace: &Path) -> Result<(), String> {
+    fs::remove_dir_all(workspace).map_err(|_| format!("Failed delete workspace {}", workspace.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_existing_workspaces() {
+        let workspace = env::temp_dir().join(format!("rss-existing-workspace-{}", process::id()));
+        let _ = fs::remove_dir_all(&workspace);
+        fs::create_dir_all(workspace.join("src")).unwrap();
+        fs::write(workspace.join("src").join("main.rs"), "fn main() {}").unwrap();
+
+        assert!(create_new_workspace(&workspace).unwrap_err().contains("already exists"));
+        assert_eq!(fs::read_to_string(workspace.join("src").join("main.rs")).unwrap(), "fn main() {}");
+        assert_eq!(fs::read_dir(&workspace).unwrap().count(), 1);
+        fs::remove_dir_all(&workspace).unwrap();
+
+        create_new_workspace(&workspace).unwrap();
+        assert_eq!(fs::read_dir(&workspace).unwrap().count(), 0);
+        fs::remove_dir_all(&workspace).unwrap();
     }
-    else {
-        fs::remove_dir_all(directory.join("target")).map_err(|_| "Failed delete target".to_string())?;
-        fs::remove_dir_all(directory.join("src")).map_err(|_| "Failed delete src".to_string())?;
-        fs::remove_file(directory.join("Cargo.toml")).map_err(|_| "Failed delete Cargo.toml".to_string())?;
-        fs::remove_file(directory.join("Cargo.lock")).map_err(|_| "Failed delete Cargo.lock".to_string())?;
+
+    #[test]
+    fn keeps_binary_artifacts() {
+        let line = r#"{"reason":"compiler-artifact","package_id":"path+file:///cache/workspaces/test-1-0#test@0.1.0","manifest_path":"/cache/workspaces/test-1-0/Cargo.toml","target":{"kind":["bin"],"crate_types":["bin"],"name":"test","src_path":"/cache/workspaces/test-1-0/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"profile":{"opt_level":"3","debuginfo":0,"debug_assertions":false,"overflow_checks":false,"test":false},"features":[],"filenames":["/cache/target/release/test"],"executable":"/cache/target/release/test","fresh":false}"#;
+        assert_eq!(handle_cargo_message(line, None), Some(PathBuf::from("/cache/target/release/test")));
     }
-    Ok(())
-}
\ No newline at end of file
+
+    #[test]
+    fn ignores_other_artifacts() {
+        let build_script = r#"{"reason":"compiler-artifact","package_id":"registry+https://github.com/rust-lang/crates.io-index#libc@0.2.155","manifest_path":"/registry/libc-0.2.155/Cargo.toml","target":{"kind":["custom-build"],"crate_types":["bin"],"name":"build-script-build","src_path":"/registry/libc-0.2.155/build.rs","edition":"2015","doc":false,"doctest":false,"test":false},"features":["default","std"],"filenames":["/cache/target/release/build/libc-2b1a7a1dc5c3b1f5/build-script-build"],"executable":"/cache/target/release/build/libc-2b1a7a1dc5c3b1f5/build-script-build","fresh":true}"#;
+        assert_eq!(handle_cargo_message(build_script, None), None);
+
+        let library = r#"{"reason":"compiler-artifact","package_id":"registry+https://github.com/rust-lang/crates.io-index#libc@0.2.155","manifest_path":"/registry/libc-0.2.155/Cargo.toml","target":{"kind":["lib"],"crate_types":["lib"],"name":"libc","src_path":"/registry/libc-0.2.155/src/lib.rs","edition":"2015","doc":true,"doctest":true,"test":true},"features":["default","std"],"filenames":["/cache/target/release/deps/liblibc-6f2ac2ba8a1c2d34.rlib"],"executable":null,"fresh":true}"#;
+        assert_eq!(handle_cargo_message(library, None), None);
+
+        let build_script_executed = r#"{"reason":"build-script-executed","package_id":"registry+https://github.com/rust-lang/crates.io-index#libc@0.2.155","linked_libs":[],"linked_paths":[],"cfgs":["freebsd11"],"env":[],"out_dir":"/cache/target/release/build/libc-0d8c6e2a4f1b3c5d/out"}"#;
+        assert_eq!(handle_cargo_message(build_script_executed, None), None);
+        assert_eq!(handle_cargo_message(r#"{"reason":"build-finished","success":true}"#, None), None);
+    }
+
+    #[test]
+    fn passes_through_other_lines() {
+        assert_eq!(handle_cargo_message("warning: build script printed this", None), None);
+        assert_eq!(handle_cargo_message("", None), None);
+        assert_eq!(handle_cargo_message(r#"{"reason":"compiler-message","message":{"rendered":"warning: unused\n"}}"#, None), None);
+    }
+}
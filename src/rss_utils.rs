@@ -1,8 +1,25 @@
-use std::fs;
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
 use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{Read, Write};
+use std::ops::Range;
 use std::path::Path;
+use std::process;
+use std::sync::OnceLock;
+use base64::Engine;
+use base64::read::DecoderReader;
+use base64::engine::general_purpose::STANDARD;
+use derive_getters::Getters;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use sha2::{Digest, Sha256};
 
+use crate::binary_utils::{check_executable_format, ExecutableFormat, EXECUTABLE_HEADER_LENGTH};
+use crate::cache_utils::cache_key;
 use crate::config::Config;
+use crate::fingerprint::{runs_on, Fingerprint};
+use crate::history_utils::record_version;
 
 pub fn check_file(rss_file: &Path) -> Result<(), String> {
     if !rss_file.is_file() {
@@ -13,210 +30,1235 @@ pub fn check_file(rss_file: &Path) -> Result<(), String> {
     }
 }
 
-pub fn get_cargo_and_source_rss(rss_file: &Path) -> Result<(String, String), String> {
-    let file_name = rss_file.file_stem().unwrap();
+/// The `name` in the `[package]` section of a manifest, if it is a quoted string
+pub fn package_name(cargo_content: &str) -> Option<&str> {
+    package_name_range(cargo_content).map(|r| &cargo_content[r])
+}
 
-    let contents = fs::read(&rss_file).map_err(|_| format!("Failed read [{}]", rss_file.display()))?;
+/// Replaces the `name` in the `[package]` section of a manifest, leaving the rest of it untouched.
+/// Returns `None` if the manifest has no name that can be replaced.
+pub fn rename_package(cargo_content: &str, name: &str) -> Option<String> {
+    let range = package_name_range(cargo_content)?;
+    Some(format!("{}{name}{}", &cargo_content[..range.start], &cargo_content[range.end..]))
+}
 
-    if contents.is_empty() {
-        return Ok((
-            include_str!("default_cargo").replace("$$$$", file_name.to_str().unwrap()),
-            include_str!("default_main").to_string()
-        ));
-    } else {
-        'err_loop: loop {
-            let mut contents: &[u8] = &contents;
-            while contents[contents.len() - 1] == 10 || contents[contents.len() - 1] == 10 {
-                contents = &contents[..contents.len() - 1];
-                if contents.len() == 0 {
-                    break;
-                }
+/// Byte range of the package name between its quotes
+fn package_name_range(cargo_content: &str) -> Option<Range<usize>> {
+    let mut in_package = false;
+    let mut line_start = 0;
+    for line in cargo_content.split_inclusive('\n') {
+        let start = line_start;
+        line_start += line.len();
+
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_package = trimmed == "[package]";
+            continue;
+        }
+        let value = trimmed.strip_prefix("name").and_then(|l| l.trim_start().strip_prefix('='));
+        let Some(value) = value.filter(|_| in_package).map(str::trim_start) else {
+            continue;
+        };
+
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let length = value[1..].find(quote)?;
+        let value_start = start + line.len() - line.trim_start().len() + trimmed.len() - value.len() + 1;
+        return Some(value_start..value_start + length);
+    }
+    None
+}
+
+/// The `[package.metadata.rss]` table of a manifest, where scripts keep their rss settings. Empty if the manifest has
+/// none or can't be parsed, in which case Cargo reports the error when building.
+pub fn rss_metadata(cargo_content: &str) -> toml::Table {
+    let Ok(manifest) = cargo_content.parse::<toml::Table>() else {
+        return toml::Table::new();
+    };
+    manifest.get("package").and_then(|p| p.get("metadata")?.get("rss")?.as_table()).cloned().unwrap_or_default()
+}
+
+/// Adds `key` with the string `value` to the `[package.metadata.rss]` table of a manifest, leaving the rest of it
+/// untouched, and appending the table if there is none. Returns `None` if the table is written in a way that can't be
+/// added to, such as an inline table.
+pub fn add_rss_metadata(cargo_content: &str, key: &str, value: &str) -> Option<String> {
+    let entry = format!("{key} = {}", toml::Value::String(value.to_string()));
+    let mut line_end = 0;
+    for line in cargo_content.split_inclusive('\n') {
+        line_end += line.len();
+        if line.trim() == "[package.metadata.rss]" {
+            let (before, after) = cargo_content.split_at(line_end);
+            let separator = if line.ends_with('\n') { "" } else { "\n" };
+            let newline = if line.ends_with("\r\n") { "\r\n" } else { "\n" };
+            return Some(format!("{before}{separator}{entry}{newline}{after}"));
+        }
+    }
+
+    let manifest = cargo_content.parse::<toml::Table>().ok()?;
+    if manifest.get("package").and_then(|p| p.get("metadata")?.get("rss")).is_some() {
+        return None;
+    }
+    let separator = if cargo_content.is_empty() || cargo_content.ends_with('\n') { "" } else { "\n" };
+    Some(format!("{cargo_content}{separator}\n[package.metadata.rss]\n{entry}\n"))
+}
+
+/// Writes a new rss file, failing if it already exists, with the permissions of `original`
+pub fn create_rss_file(rss_file: &Path, contents: &[u8], original: &fs::Metadata) -> Result<(), String> {
+    write_synced(rss_file, contents, None)?;
+    fs::set_permissions(rss_file, original.permissions()).map_err(|_| format!("Failed to set permissions of [{}]", rss_file.display()))
+}
+
+/// How the compiled binary is stored in the trailer of an rss file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PayloadEncoding {
+    /// Base64 text followed by `:<encoded length>b`
+    Base64,
+    /// Raw bytes followed by a little-endian `u32` length and `r`
+    Raw
+}
+
+impl PayloadEncoding {
+    fn from_marker(marker: u8) -> Option<PayloadEncoding> {
+        match marker {
+            b'b' => Some(PayloadEncoding::Base64),
+            b'r' => Some(PayloadEncoding::Raw),
+            _ => None
+        }
+    }
+
+    fn marker(&self) -> u8 {
+        match self {
+            PayloadEncoding::Base64 => b'b',
+            PayloadEncoding::Raw => b'r'
+        }
+    }
+}
+
+/// How the compiled binary is compressed before being encoded.
+/// Written after the encoding marker, or omitted if uncompressed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    /// Raw deflate stream. The level is only used when writing.
+    Deflate(u32)
+}
+
+impl Compression {
+    const DEFLATE_MARKER: u8 = b'd';
+
+    fn from_config(config: &Config) -> Compression {
+        match config.compression() {
+            Some(level) => Compression::Deflate(*level),
+            None => Compression::None
+        }
+    }
+
+    fn compress(&self, binary: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => binary.to_vec(),
+            Compression::Deflate(level) => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::new(*level));
+                encoder.write_all(binary).unwrap();
+                encoder.finish().unwrap()
             }
+        }
+    }
+}
 
-            if contents.len() < 2 {
-                break;
+/// The part of an rss file that failed to parse
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Section {
+    Manifest,
+    Source,
+    PayloadStart,
+    Payload,
+    PayloadLength,
+    EncodingMarker,
+    Hash,
+    Fingerprint,
+    End
+}
+
+impl Display for Section {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Section::Manifest => "manifest",
+            Section::Source => "source",
+            Section::PayloadStart => "payload start",
+            Section::Payload => "payload",
+            Section::PayloadLength => "payload length",
+            Section::EncodingMarker => "encoding marker",
+            Section::Hash => "hash",
+            Section::Fingerprint => "build fingerprint",
+            Section::End => "end of file"
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ParseError {
+    /// A fixed delimiter such as `*/` was not where it should be
+    MissingDelimiter { offset: usize, section: Section, delimiter: &'static str },
+    /// A `:` separated field is missing its separator
+    MissingField { offset: usize, section: Section },
+    InvalidNumber { offset: usize, section: Section, found: String },
+    UnknownEncoding { offset: usize, found: u8 },
+    /// The payload length field does not match the size of the payload
+    PayloadLengthMismatch { offset: usize, expected: usize, found: usize },
+    InvalidBase64 { offset: usize },
+    InvalidFingerprint { offset: usize },
+    /// The payload could not be decompressed
+    InvalidCompressedData { offset: usize },
+    InvalidUtf8 { offset: usize, section: Section }
+}
+
+impl ParseError {
+    /// Byte offset into the file where the error was found
+    pub fn offset(&self) -> usize {
+        match self {
+            ParseError::MissingDelimiter { offset, .. } |
+            ParseError::MissingField { offset, .. } |
+            ParseError::InvalidNumber { offset, .. } |
+            ParseError::UnknownEncoding { offset, .. } |
+            ParseError::PayloadLengthMismatch { offset, .. } |
+            ParseError::InvalidBase64 { offset } |
+            ParseError::InvalidFingerprint { offset } |
+            ParseError::InvalidCompressedData { offset } |
+            ParseError::InvalidUtf8 { offset, .. } => *offset
+        }
+    }
+
+    pub fn section(&self) -> Section {
+        match self {
+            ParseError::MissingDelimiter { section, .. } |
+            ParseError::MissingField { section, .. } |
+            ParseError::InvalidNumber { section, .. } |
+            ParseError::InvalidUtf8 { section, .. } => *section,
+            ParseError::UnknownEncoding { .. } => Section::EncodingMarker,
+            ParseError::InvalidFingerprint { .. } => Section::Fingerprint,
+            ParseError::PayloadLengthMismatch { .. } | ParseError::InvalidBase64 { .. } | ParseError::InvalidCompressedData { .. } => Section::Payload
+        }
+    }
+
+    /// What the parser expected to find
+    pub fn expected(&self) -> String {
+        match self {
+            ParseError::MissingDelimiter { delimiter, .. } => format!("`{}`", delimiter.escape_debug()),
+            ParseError::MissingField { section, .. } => format!("`:` before the {section}"),
+            ParseError::InvalidNumber { section: Section::Hash, found, .. } => format!("`{SHA256_PREFIX}` followed by 64 hex digits or a decimal legacy hash, found `{}`", found.escape_debug()),
+            ParseError::InvalidNumber { section, found, .. } => format!("a decimal {section}, found `{}`", found.escape_debug()),
+            ParseError::UnknownEncoding { found, .. } => format!("`b` (base64) or `r` (raw), found `{}`", found.escape_ascii()),
+            ParseError::PayloadLengthMismatch { expected, .. } => format!("{expected} bytes of payload"),
+            ParseError::InvalidBase64 { .. } => "valid base64".to_string(),
+            ParseError::InvalidFingerprint { .. } => "`target`, `profile` and `features` fields separated by `;`".to_string(),
+            ParseError::InvalidCompressedData { .. } => "a valid deflate stream".to_string(),
+            ParseError::InvalidUtf8 { section, .. } => format!("UTF-8 text in the {section}")
+        }
+    }
+
+    /// A suggestion for what is likely to have gone wrong
+    pub fn hint(&self) -> Option<String> {
+        Some(match self {
+            ParseError::MissingDelimiter { section: Section::End, .. } => "the file should end with `*/` - it may have been truncated".to_string(),
+            ParseError::MissingDelimiter { section: Section::Manifest, delimiter: "/*", .. } => "the file should start with the Cargo.toml manifest inside `/*` and `*/`".to_string(),
+            ParseError::MissingDelimiter { section: Section::Manifest, .. } => "the manifest comment must be closed with `*/` followed by a new line".to_string(),
+            ParseError::MissingDelimiter { section: Section::PayloadStart, .. } => "the payload length may be wrong, or text may have been added after the payload started".to_string(),
+            ParseError::PayloadLengthMismatch { expected, found, .. } if found < expected => format!("payload length says {expected} bytes but only {found} are present"),
+            ParseError::PayloadLengthMismatch { expected, found, .. } => format!("payload length says {expected} bytes but {found} are present"),
+            ParseError::InvalidBase64 { .. } | ParseError::InvalidCompressedData { .. } => "the payload has been modified - rebuild the script with `rss edit`".to_string(),
+            _ => return None
+        })
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "byte {} ({}): expected {}", self.offset(), self.section(), self.expected())?;
+        if let Some(hint) = self.hint() {
+            write!(f, "\nhint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+const SHA256_PREFIX: &str = "sha256-";
+
+/// Fingerprint of the manifest and source that the embedded binary was built from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SourceHash {
+    /// `sha256-<hex>`: SHA-256 of the manifest and source, each prefixed with its length as a little-endian `u64`
+    /// and with CRLF line endings normalised to LF. The target is recorded separately in the fingerprint.
+    Sha256([u8; 32]),
+    /// Decimal output of `DefaultHasher`, written by older versions. Not stable across Rust releases.
+    Legacy(u64)
+}
+
+impl SourceHash {
+    pub fn new(cargo_content: &str, rust_content: &str) -> SourceHash {
+        let mut h = Sha256::new();
+        for content in [cargo_content, rust_content] {
+            let content = content.replace("\r\n", "\n");
+            h.update((content.len() as u64).to_le_bytes());
+            h.update(content.as_bytes());
+        }
+        SourceHash::Sha256(h.finalize().into())
+    }
+
+    fn legacy(cargo_content: &str, rust_content: &str) -> SourceHash {
+        let mut h = DefaultHasher::new();
+        cargo_content.replace("\r\n", "\n").hash(&mut h);
+        rust_content.replace("\r\n", "\n").hash(&mut h);
+        PLATFORM.hash(&mut h);
+        SourceHash::Legacy(h.finish())
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, SourceHash::Legacy(_))
+    }
+
+    /// Whether this hash was calculated from the given manifest and source on this platform
+    pub fn matches(&self, cargo_content: &str, rust_content: &str) -> bool {
+        match self {
+            SourceHash::Sha256(_) => *self == SourceHash::new(cargo_content, rust_content),
+            SourceHash::Legacy(_) => *self == SourceHash::legacy(cargo_content, rust_content)
+        }
+    }
+
+    fn parse(field: &[u8]) -> Option<SourceHash> {
+        let field = std::str::from_utf8(field).ok()?;
+        let Some(hex) = field.strip_prefix(SHA256_PREFIX) else {
+            return field.parse().ok().map(SourceHash::Legacy);
+        };
+
+        if hex.len() != 64 {
+            return None;
+        }
+        let mut digest = [0; 32];
+        for (i, byte) in digest.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some(SourceHash::Sha256(digest))
+    }
+}
+
+impl Display for SourceHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceHash::Sha256(digest) => {
+                f.write_str(SHA256_PREFIX)?;
+                digest.iter().try_for_each(|b| write!(f, "{b:02x}"))
             }
+            SourceHash::Legacy(hash) => write!(f, "{hash}")
+        }
+    }
+}
 
-            let contents = &contents[..contents.len() - 2]; // Remove raw / base64) and '*/'
+const CORRUPT_BINARY: &str = "Embedded binary is corrupt - rss check-format shows where";
 
-            let mut i = contents.len() - 1;
-            loop {
-                if contents[i] == 58 {
-                    break;
-                }
-                if i == 0 {
-                    break 'err_loop;
+/// A compiled binary embedded in an rss file
+#[derive(Getters)]
+pub struct Payload {
+    encoding: PayloadEncoding,
+    compression: Compression,
+    /// The compressed binary as it is stored in the file, without any line breaks in base64
+    #[getter(skip)]
+    data: Vec<u8>,
+    /// Decompressed binary, decoded from `data` when it is first needed
+    #[getter(skip)]
+    binary: OnceLock<Vec<u8>>,
+    /// Hash of the source the binary was built from
+    hash: SourceHash,
+    /// How the binary was built - `None` for files written by older versions
+    fingerprint: Option<Fingerprint>
+}
+
+impl Payload {
+    /// Creates a payload for `binary`, using the encoding and compression from the config
+    pub fn new(config: &Config, cargo_content: &str, rust_content: &str, binary: Vec<u8>, fingerprint: Fingerprint) -> Payload {
+        let encoding = if *config.base64() { PayloadEncoding::Base64 } else { PayloadEncoding::Raw };
+        Payload::encode(encoding, Compression::from_config(config), binary, SourceHash::new(cargo_content, rust_content), Some(fingerprint))
+    }
+
+    /// A payload with no binary, recording only how the script should be built. Used for scripts whose binaries are
+    /// kept in the user cache.
+    pub fn source_only(cargo_content: &str, rust_content: &str, fingerprint: Fingerprint) -> Payload {
+        Payload::encode(PayloadEncoding::Base64, Compression::None, Vec::new(), SourceHash::new(cargo_content, rust_content), Some(fingerprint))
+    }
+
+    fn encode(encoding: PayloadEncoding, compression: Compression, binary: Vec<u8>, hash: SourceHash, fingerprint: Option<Fingerprint>) -> Payload {
+        let data = compression.compress(&binary);
+        let data = match encoding {
+            PayloadEncoding::Base64 => STANDARD.encode(data).into_bytes(),
+            PayloadEncoding::Raw => data
+        };
+        Payload { encoding, compression, data, binary: OnceLock::from(binary), hash, fingerprint }
+    }
+
+    pub fn is_source_only(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The decompressed binary, decoded from the file the first time it is needed
+    pub fn binary(&self) -> Result<&[u8], String> {
+        if let Some(binary) = self.binary.get() {
+            return Ok(binary);
+        }
+        let mut binary = Vec::new();
+        self.decoder().read_to_end(&mut binary).map_err(|_| CORRUPT_BINARY.to_string())?;
+        Ok(self.binary.get_or_init(|| binary))
+    }
+
+    /// Checks that the binary can run on this machine, decoding no more of it than its header
+    pub fn check_executable_format(&self) -> Result<(), String> {
+        check_executable_format(&self.header()?)
+    }
+
+    /// The format of the binary, if it is one that is recognised
+    pub fn executable_format(&self) -> Option<ExecutableFormat> {
+        ExecutableFormat::detect(&self.header().ok()?)
+    }
+
+    /// Enough of the start of the binary to detect its format
+    fn header(&self) -> Result<Vec<u8>, String> {
+        if let Some(binary) = self.binary.get() {
+            return Ok(binary[..binary.len().min(EXECUTABLE_HEADER_LENGTH)].to_vec());
+        }
+        let mut header = Vec::new();
+        self.decoder().take(EXECUTABLE_HEADER_LENGTH as u64).read_to_end(&mut header).map_err(|_| CORRUPT_BINARY.to_string())?;
+        Ok(header)
+    }
+
+    /// Reads the binary out of `data`
+    fn decoder(&self) -> Box<dyn Read + '_> {
+        let data: Box<dyn Read> = match self.encoding {
+            PayloadEncoding::Base64 => Box::new(DecoderReader::new(self.data.as_slice(), &STANDARD)),
+            PayloadEncoding::Raw => Box::new(self.data.as_slice())
+        };
+        match self.compression {
+            Compression::None => data,
+            Compression::Deflate(_) => Box::new(DeflateDecoder::new(data))
+        }
+    }
+
+    /// Identifies the binary in the cache by the source and settings it was built from, so that finding an extracted
+    /// copy doesn't need it to be decoded
+    pub fn cache_key(&self) -> String {
+        let fingerprint = self.fingerprint.as_ref().map(Fingerprint::to_string).unwrap_or_default();
+        cache_key(&[b"embedded", self.hash.to_string().as_bytes(), fingerprint.as_bytes(), &(self.data.len() as u64).to_le_bytes()])
+    }
+
+    /// The target triple the binary was built for, if known
+    pub fn target(&self) -> Option<&str> {
+        self.fingerprint.as_ref().map(|f| f.target().as_str())
+    }
+
+    /// Parses the last payload block in `contents`, returning the contents before the block's `/*` delimiter
+    /// and whether the block was marked as following another payload block. The binary is only checked and decoded
+    /// if `decode` is set.
+    fn parse(contents: &[u8], decode: bool) -> Result<(&[u8], Payload, bool), ParseError> {
+        let contents = contents.strip_suffix(b"*/")
+            .ok_or(ParseError::MissingDelimiter { offset: contents.len().saturating_sub(2), section: Section::End, delimiter: "*/" })?;
+
+        let (mut contents, mut hash) = split_last(contents, b':')
+            .ok_or(ParseError::MissingField { offset: contents.len(), section: Section::Hash })?;
+
+        // Hashes never contain `=` so this is the fingerprint
+        let mut fingerprint = None;
+        if hash.contains(&b'=') {
+            let field = hash;
+            fingerprint = Some(std::str::from_utf8(field).ok().and_then(Fingerprint::parse)
+                .ok_or(ParseError::InvalidFingerprint { offset: contents.len() + 1 })?);
+            (contents, hash) = split_last(contents, b':')
+                .ok_or(ParseError::MissingField { offset: contents.len(), section: Section::Hash })?;
+        }
+
+        let hash = SourceHash::parse(hash)
+            .ok_or_else(|| ParseError::InvalidNumber { offset: contents.len() + 1, section: Section::Hash, found: String::from_utf8_lossy(hash).to_string() })?;
+
+        let (mut marker, mut contents) = contents.split_last()
+            .ok_or(ParseError::MissingField { offset: 0, section: Section::EncodingMarker })?;
+        let mut compression = Compression::None;
+        if *marker == Compression::DEFLATE_MARKER {
+            compression = Compression::Deflate(flate2::Compression::default().level());
+            (marker, contents) = contents.split_last()
+                .ok_or(ParseError::MissingField { offset: 0, section: Section::EncodingMarker })?;
+        }
+        let encoding = PayloadEncoding::from_marker(*marker)
+            .ok_or(ParseError::UnknownEncoding { offset: contents.len(), found: *marker })?;
+
+        let (contents, data) = match encoding {
+            PayloadEncoding::Base64 => {
+                let (contents, payload_length) = split_last(contents, b':')
+                    .ok_or(ParseError::MissingField { offset: contents.len(), section: Section::PayloadLength })?;
+                let payload_length = parse_decimal(payload_length)
+                    .ok_or_else(|| ParseError::InvalidNumber { offset: contents.len() + 1, section: Section::PayloadLength, found: String::from_utf8_lossy(payload_length).to_string() })?;
+
+                // `*` is not in the base64 alphabet so the payload starts after the last `/*`,
+                // which lets line breaks added by editors or git be skipped
+                let Some(payload_start) = contents.windows(2).rposition(|w| w == b"/*") else {
+                    return Err(ParseError::MissingDelimiter { offset: 0, section: Section::PayloadStart, delimiter: "/*" });
+                };
+                let mut payload_start = payload_start + 2;
+                if contents.get(payload_start) == Some(&FOLLOWING_PAYLOAD) {
+                    payload_start += 1;
                 }
-                i -= 1;
-            }
-            let (contents, hash) = (&contents[..i], &contents[i+1..]);
-            let Ok(_hash): Result<u64, _> = String::from_utf8_lossy(hash).parse() else { break; };
+                let (contents, payload) = contents.split_at(payload_start);
+                let stripped = payload.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect::<Vec<_>>();
 
-            if contents.len() < 1 {
-                break;
+                if stripped.len() != payload_length {
+                    return Err(ParseError::PayloadLengthMismatch { offset: contents.len(), expected: payload_length, found: stripped.len() });
+                }
+                if decode {
+                    STANDARD.decode(&stripped).map_err(|e| ParseError::InvalidBase64 {
+                        offset: match e {
+                            base64::DecodeError::InvalidByte(i, _) | base64::DecodeError::InvalidLastSymbol(i, _) => contents.len()
+                                + payload.iter().enumerate().filter(|(_, b)| !b.is_ascii_whitespace()).nth(i).unwrap().0,
+                            _ => contents.len()
+                        }
+                    })?;
+                }
+                (contents, stripped)
             }
+            PayloadEncoding::Raw => {
+                let Some(length_start) = contents.len().checked_sub(4) else {
+                    return Err(ParseError::MissingField { offset: 0, section: Section::PayloadLength });
+                };
+                let (contents, payload_length) = contents.split_at(length_start);
+                let payload_length = u32::from_le_bytes(payload_length.try_into().unwrap()) as usize;
 
-            let is_b64 = contents[contents.len() - 1];
-            let contents = &contents[..contents.len() - 1];
-
-            let (contents, compiled_length) = if is_b64 == 98 {
-                let mut i = contents.len() - 1;
-                loop {
-                    if contents[i] == 58 {
-                        break;
-                    }
-                    if i == 0 {
-                        break 'err_loop;
-                    }
-                    i -= 1;
+                if payload_length > contents.len() {
+                    return Err(ParseError::PayloadLengthMismatch { offset: contents.len(), expected: payload_length, found: contents.len() });
                 }
-                let (contents, compiled_length) = (&contents[..i], &contents[i+1..]);
-                let Ok(compiled_length) = String::from_utf8_lossy(compiled_length).parse() else { break; };
-                (contents, compiled_length)
-            } else {
-                let (contents, compiled_length) = (&contents[..contents.len() - 4], &contents[contents.len() - 4..]);
-                let compiled_length = u32::from_le_bytes(compiled_length.try_into().unwrap()) as usize;
-                (contents, compiled_length)
-            };
-
-            if contents.len() < compiled_length as usize + 3 {
-                break;
+                let (contents, data) = contents.split_at(contents.len() - payload_length);
+                (contents, data.to_vec())
             }
-            let contents = &contents[..contents.len() - compiled_length as usize - 3]; // Remove '\n/*'
-            let contents = String::from_utf8_lossy(contents);
+        };
+
+        let payload = Payload { encoding, compression, data, binary: OnceLock::new(), hash, fingerprint };
+        if decode {
+            payload.binary().map_err(|_| ParseError::InvalidCompressedData { offset: contents.len() })?;
+        }
+
+        let (contents, following) = match contents.strip_suffix(&[FOLLOWING_PAYLOAD]) {
+            Some(contents) => (contents, true),
+            None => (contents, false)
+        };
+        let contents = contents.strip_suffix(b"/*")
+            .ok_or(ParseError::MissingDelimiter { offset: contents.len().saturating_sub(2), section: Section::PayloadStart, delimiter: "/*" })?;
+
+        Ok((contents, payload, following))
+    }
 
-            let Some(toml_end) = contents.find("*/") else { break; };
-            let (cargo_toml, rust_contents) = contents.split_at(toml_end);
+    fn serialize(&self, output_data: &mut Vec<u8>) {
+        output_data.extend(&self.data);
+        match self.encoding {
+            PayloadEncoding::Base64 => {
+                output_data.push(b':');
+                output_data.extend(format!("{}", self.data.len()).as_bytes());
+            }
+            PayloadEncoding::Raw => output_data.extend(&(self.data.len() as u32).to_le_bytes())
+        }
+        output_data.push(self.encoding.marker());
+        if let Compression::Deflate(_) = self.compression {
+            output_data.push(Compression::DEFLATE_MARKER);
+        }
 
-            if cargo_toml.len() < 2 { break; }
-            let (cargo_toml, rust_contents) = (&cargo_toml[2..], &rust_contents[3..]); // Remove '/*' and '*/\n'
+        output_data.push(b':');
+        output_data.extend(self.hash.to_string().as_bytes());
 
-            return Ok((cargo_toml.to_string(), rust_contents.to_string()));
+        if let Some(fingerprint) = &self.fingerprint {
+            output_data.push(b':');
+            output_data.extend(fingerprint.to_string().as_bytes());
         }
-        return Err("Improperly formatted rss file".to_string());
     }
 }
 
-pub fn get_binary_rss(rss_file: &Path) -> Result<(Vec<u8>, u64), String> {
-    let compiled = fs::read(&rss_file).map_err(|_| format!("Failed read [{}]", rss_file.display()))?;
+/// Marks a payload block that follows another, so that the first block is not mistaken for part of the source.
+/// Not in the base64 alphabet.
+const FOLLOWING_PAYLOAD: u8 = b'#';
 
-    if compiled.is_empty() {
-        return Err("RSS file is empty - cannot run".to_string());
-    }
+/// An rss file:
+/// ```text
+/// /*<Cargo.toml>*/
+/// <main.rs>
+/// /*<payload><payload length><encoding marker>[<compression marker>]:<hash>[:<fingerprint>]*/
+/// /*#<payload><payload length><encoding marker>[<compression marker>]:<hash>:<fingerprint>*/
+/// ...
+/// ```
+/// Each payload is built for a different target triple. A payload with no binary makes a source-only script, which is
+/// built into the user cache when run.
+#[derive(Getters)]
+pub struct RssFile {
+    cargo_content: String,
+    rust_content: String,
+    /// Empty if the file has not been built yet
+    payloads: Vec<Payload>
+}
 
-    'err_loop: loop {
-        let mut compiled: &[u8] = &compiled;
-        while compiled[compiled.len() - 1] == 10 || compiled[compiled.len() - 1] == 10 {
-            compiled = &compiled[..compiled.len() - 1];
-            if compiled.len() == 0 {
-                break;
-            }
+impl RssFile {
+    /// Creates an rss file with no payloads
+    pub fn new(cargo_content: String, rust_content: String) -> RssFile {
+        RssFile {
+            cargo_content,
+            rust_content,
+            payloads: Vec::new()
         }
+    }
 
-        if compiled.len() < 2 {
-            break;
+    /// The contents of a new, unbuilt script
+    fn new_default(rss_file: &Path) -> RssFile {
+        let file_name = rss_file.file_stem().unwrap();
+        RssFile::new(
+            include_str!("default_cargo").replace("$$$$", file_name.to_str().unwrap()),
+            include_str!("default_main").to_string()
+        )
+    }
+
+    /// Reads and parses an rss file. An empty file is treated as a new script.
+    pub fn read(rss_file: &Path) -> Result<RssFile, String> {
+        let contents = fs::read(rss_file).map_err(|_| format!("Failed read [{}]", rss_file.display()))?;
+
+        if contents.is_empty() {
+            Ok(RssFile::new_default(rss_file))
+        }
+        else {
+            RssFile::parse_lazily(&contents).map_err(|e| format!("Improperly formatted rss file [{}] at {e}", rss_file.display()))
         }
-        let compiled = &compiled[..compiled.len() - 2]; // Remove '*/'
+    }
+
+    /// Parses an rss file from the end backwards. Every intermediate slice starts at the beginning of the
+    /// file so its length is the offset of whatever comes after it.
+    ///
+    /// CRLF line endings and trailing whitespace are accepted, as are line breaks inside a base64 payload.
+    pub fn parse(contents: &[u8]) -> Result<RssFile, ParseError> {
+        RssFile::parse_payloads(contents, true)
+    }
+
+    /// Like [`RssFile::parse`], but leaves each binary to be checked and decoded when it is first needed, as most runs
+    /// use at most one of them
+    pub fn parse_lazily(contents: &[u8]) -> Result<RssFile, ParseError> {
+        RssFile::parse_payloads(contents, false)
+    }
+
+    fn parse_payloads(contents: &[u8], decode: bool) -> Result<RssFile, ParseError> {
+        let mut contents = contents.trim_ascii_end();
+        let mut payloads = Vec::new();
 
-        let mut i = compiled.len() - 1;
         loop {
-            if compiled[i] == 58 {
+            let (rest, payload, following) = Payload::parse(contents, decode)?;
+            payloads.push(payload);
+
+            contents = rest.strip_suffix(b"\n")
+                .map(|c| c.strip_suffix(b"\r").unwrap_or(c))
+                .ok_or(ParseError::MissingDelimiter { offset: rest.len(), section: Section::PayloadStart, delimiter: "\n/*" })?;
+
+            if !following {
                 break;
             }
-            if i == 0 {
-                break 'err_loop;
-            }
-            i -= 1;
         }
-        let (compiled, hash) = (&compiled[..i], &compiled[i+1..]);
-        let Ok(hash) = String::from_utf8_lossy(hash).parse() else { break; };
+        payloads.reverse();
+
+        let contents = contents.strip_prefix(b"/*")
+            .ok_or(ParseError::MissingDelimiter { offset: 0, section: Section::Manifest, delimiter: "/*" })?;
 
-        if compiled.len() < 1 {
-            break;
+        let manifest_end = contents.windows(2).position(|w| w == b"*/");
+        let Some((manifest_end, delimiter_length)) = manifest_end.and_then(|i| match &contents[i + 2..] {
+            [b'\n', ..] => Some((i, 3)),
+            [b'\r', b'\n', ..] => Some((i, 4)),
+            _ => None
+        }) else {
+            return Err(ParseError::MissingDelimiter { offset: 2 + manifest_end.unwrap_or(contents.len()), section: Section::Manifest, delimiter: "*/\n" });
+        };
+        let (cargo_content, rust_content) = (&contents[..manifest_end], &contents[manifest_end + delimiter_length..]);
+        let cargo_content = std::str::from_utf8(cargo_content)
+            .map_err(|e| ParseError::InvalidUtf8 { offset: 2 + e.valid_up_to(), section: Section::Manifest })?;
+        let rust_content = std::str::from_utf8(rust_content)
+            .map_err(|e| ParseError::InvalidUtf8 { offset: 2 + manifest_end + delimiter_length + e.valid_up_to(), section: Section::Source })?;
+
+        Ok(RssFile {
+            cargo_content: cargo_content.to_string(),
+            rust_content: rust_content.to_string(),
+            payloads
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut output_data: Vec<u8> = Vec::new();
+
+        output_data.extend(b"/*");
+        output_data.extend(self.cargo_content.as_bytes());
+        output_data.extend(b"*/\n");
+        output_data.extend(self.rust_content.as_bytes());
+
+        for (i, payload) in self.payloads.iter().enumerate() {
+            output_data.extend(b"\n/*");
+            if i != 0 {
+                output_data.push(FOLLOWING_PAYLOAD);
+            }
+            payload.serialize(&mut output_data);
+            output_data.extend(b"*/");
         }
 
-        let is_b64 = compiled[compiled.len() - 1];
-        let compiled = &compiled[..compiled.len() - 1];
+        output_data
+    }
 
-        let (compiled, compiled_length) = if is_b64 == 98 {
-            let mut i = compiled.len() - 1;
-            loop {
-                if compiled[i] == 58 {
-                    break;
-                }
-                if i == 0 {
-                    break 'err_loop;
-                }
-                i -= 1;
+    pub fn write(&self, config: &Config, rss_file: &Path) -> Result<(), String> {
+        write_rss_file(config, rss_file, &self.serialize())
+    }
+
+    /// The payload built for `target`. Payloads from older versions with no recorded target are assumed to match
+    /// unless their binary was evidently built for another target.
+    pub fn payload(&self, target: &str) -> Option<&Payload> {
+        self.payloads.iter().find(|p| p.target() == Some(target))
+            .or_else(|| self.payloads.iter().find(|p| p.target().is_none() && p.executable_format().is_none_or(|f| f.is_for_target(target))))
+    }
+
+    /// The embedded binary to run on `host`: the one built for it, else one built for a target that runs on it
+    pub fn runnable_payload(&self, host: &str) -> Option<&Payload> {
+        self.payload(host).filter(|p| !p.is_source_only())
+            .or_else(|| self.payloads.iter().find(|p| !p.is_source_only() && p.target().is_some_and(|t| runs_on(t, host))))
+    }
+
+    /// Adds a payload, replacing any existing payload for the same target. Payloads from older versions with no
+    /// recorded target are kept if their binary was evidently built for another target.
+    pub fn add_payload(&mut self, payload: Payload) {
+        self.payloads.retain(|p| match p.target() {
+            Some(target) => Some(target) != payload.target(),
+            None => p.executable_format().is_some_and(|f| !payload.target().is_some_and(|t| f.is_for_target(t)))
+        });
+        self.payloads.push(payload);
+    }
+
+    /// Removes all binaries, leaving only `payload`
+    pub fn set_source_only(&mut self, payload: Payload) {
+        self.payloads.clear();
+        self.payloads.push(payload);
+    }
+
+    /// Renames the package, which removes the binaries built under the old name. Returns whether there were any, or
+    /// `None` if the manifest has no name that can be replaced.
+    pub fn rename_package(&mut self, name: &str) -> Option<bool> {
+        let cargo_content = rename_package(&self.cargo_content, name)?;
+        let had_binaries = self.has_binaries();
+        self.set_source(cargo_content, self.rust_content.clone());
+        Some(had_binaries)
+    }
+
+    /// Whether any payload contains a binary
+    pub fn has_binaries(&self) -> bool {
+        self.payloads.iter().any(|p| !p.is_source_only())
+    }
+
+    /// Names the binary built from this source with `fingerprint` in the user cache
+    pub fn cache_key(&self, fingerprint: &Fingerprint) -> String {
+        let hash = SourceHash::new(&self.cargo_content, &self.rust_content).to_string();
+        cache_key(&[hash.as_bytes(), fingerprint.to_string().as_bytes()])
+    }
+
+    /// Replaces the manifest and source, removing payloads that were built from a different version.
+    /// Returns the number of payloads removed.
+    pub fn set_source(&mut self, cargo_content: String, rust_content: String) -> usize {
+        self.cargo_content = cargo_content;
+        self.rust_content = rust_content;
+        let count = self.payloads.len();
+        self.payloads.retain(|p| p.hash.matches(&self.cargo_content, &self.rust_content));
+        count - self.payloads.len()
+    }
+
+    /// Whether `payload` was built from the embedded source
+    pub fn is_up_to_date(&self, payload: &Payload) -> bool {
+        payload.hash.matches(&self.cargo_content, &self.rust_content)
+    }
+
+    /// Replaces legacy hashes of up to date payloads with SHA-256 ones. Returns whether any were replaced.
+    pub fn upgrade_hashes(&mut self) -> bool {
+        let mut upgraded = false;
+        for payload in &mut self.payloads {
+            if payload.hash.is_legacy() && payload.hash.matches(&self.cargo_content, &self.rust_content) {
+                payload.hash = SourceHash::new(&self.cargo_content, &self.rust_content);
+                upgraded = true;
             }
-            let (compiled, compiled_length) = (&compiled[..i], &compiled[i+1..]);
-            let Ok(compiled_length) = String::from_utf8_lossy(compiled_length).parse() else { break; };
-            (compiled, compiled_length)
-        } else {
-            let (compiled, compiled_length) = (&compiled[..compiled.len() - 4], &compiled[compiled.len() - 4..]);
-            let compiled_length = u32::from_le_bytes(compiled_length.try_into().unwrap()) as usize;
-            (compiled, compiled_length)
-        };
+        }
+        upgraded
+    }
+}
+
+/// Writes an rss file so that an interruption never leaves it truncated: the contents are written and synced to a
+/// temporary file in the same directory, which is then renamed over the original. The original's permissions, and its
+/// ownership where permitted, are kept. The previous version is recorded in the history and, if enabled in the config,
+/// kept as `.rss.bak`.
+pub fn write_rss_file(config: &Config, rss_file: &Path, contents: &[u8]) -> Result<(), String> {
+    // Replace the file a symlink points to rather than the symlink
+    let rss_file = fs::canonicalize(rss_file).unwrap_or_else(|_| rss_file.to_path_buf());
+    let original = fs::metadata(&rss_file).ok();
+    if original.is_some() {
+        let previous = fs::read(&rss_file).map_err(|_| format!("Failed read [{}]", rss_file.display()))?;
+        record_version(&rss_file, &previous, *config.history())?;
+    }
 
+    let mut temp_name = OsString::from(".");
+    temp_name.push(rss_file.file_name().unwrap());
+    temp_name.push(format!(".{}.tmp", process::id()));
+    let temp_file = rss_file.with_file_name(temp_name);
 
-        if compiled_length > compiled.len() {
-            break;
+    let result = write_synced(&temp_file, contents, original.as_ref()).and_then(|()| {
+        if *config.backup() && original.is_some() {
+            let mut backup_name = rss_file.file_name().unwrap().to_os_string();
+            backup_name.push(".bak");
+            let backup_file = rss_file.with_file_name(backup_name);
+            let _ = fs::remove_file(&backup_file);
+            fs::hard_link(&rss_file, &backup_file).or_else(|_| fs::copy(&rss_file, &backup_file).map(|_| ()))
+                .map_err(|_| format!("Failed to back up [{}] to [{}]", rss_file.display(), backup_file.display()))?;
         }
-        let compiled = &compiled[compiled.len() - compiled_length..];
+        fs::rename(&temp_file, &rss_file).map_err(|_| format!("Failed write to [{}]", rss_file.display()))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_file);
+    }
+    result?;
 
-        return if is_b64 == 98 {
-            let Ok(decoded) = base64::decode(compiled) else {
-                break;
-            };
-            Ok((Vec::from(decoded), hash))
-        } else {
-            Ok((Vec::from(compiled), hash))
+    // Sync the directory so the rename itself survives a crash
+    #[cfg(target_os = "linux")]
+    if let Ok(directory) = File::open(rss_file.parent().unwrap()) {
+        let _ = directory.sync_all();
+    }
+    Ok(())
+}
+
+/// Writes `contents` to a new file and syncs it to disk, copying the permissions and ownership of `original`
+fn write_synced(path: &Path, contents: &[u8], original: Option<&fs::Metadata>) -> Result<(), String> {
+    let mut file = File::create_new(path).map_err(|_| format!("Failed to create [{}]", path.display()))?;
+    file.write_all(contents).map_err(|_| format!("Failed write to [{}]", path.display()))?;
+
+    if let Some(original) = original {
+        file.set_permissions(original.permissions()).map_err(|_| format!("Failed to set permissions of [{}]", path.display()))?;
+
+        // Only root can change the owner, but the group can be kept if the user is a member of it
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::{fchown, MetadataExt};
+            if fchown(&file, Some(original.uid()), Some(original.gid())).is_err() {
+                let _ = fchown(&file, None, Some(original.gid()));
+            }
         }
     }
-    return Err("Improperly formatted rss file".to_string());
+
+    file.sync_all().map_err(|_| format!("Failed write to [{}]", path.display()))
 }
 
-pub fn build_rss(config: &Config, rss_file: &Path, cargo_content: &str, rust_content: &str, binary: &[u8]) -> Result<(), String> {
-    let mut output_data: Vec<u8> = Vec::new();
+/// Included in legacy hashes
+#[cfg(target_os = "windows")]
+const PLATFORM: &str = "windows";
+#[cfg(target_os = "linux")]
+const PLATFORM: &str = "linux";
 
-    output_data.extend("/*".as_bytes());
-    output_data.extend(cargo_content.as_bytes());
-    output_data.extend("*/\n".as_bytes());
-    output_data.extend(rust_content.as_bytes());
-    output_data.extend("\n/*".as_bytes());
+/// 1-based line and column of a byte offset
+pub fn line_column(contents: &[u8], offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line_start = before.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    (before.iter().filter(|b| **b == b'\n').count() + 1, offset - line_start + 1)
+}
+
+/// Splits at the last occurrence of `separator`, excluding it from both sides
+fn split_last(contents: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let i = contents.iter().rposition(|b| *b == separator)?;
+    Some((&contents[..i], &contents[i + 1..]))
+}
+
+fn parse_decimal<T: std::str::FromStr>(bytes: &[u8]) -> Option<T> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
 
-    if *config.base64() {
-        let b64 = base64::encode(binary);
-        output_data.extend(b64.as_bytes());
-        output_data.push(58);
-        output_data.extend(format!("{}", b64.as_bytes().len()).as_bytes());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARGO: &str = "[package]\nname = \"test\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n";
+    const RUST: &str = "fn main() {\n    println!(\"Hello, RSS\");\n}";
+
+    fn payload(encoding: PayloadEncoding, target: &str) -> Payload {
+        payload_with(encoding, Compression::None, (0..=255).cycle().take(1000).collect(), target)
     }
-    else {
-        output_data.extend(binary);
-        output_data.extend(&(binary.len() as u32).to_le_bytes());
+
+    fn payload_with(encoding: PayloadEncoding, compression: Compression, binary: Vec<u8>, target: &str) -> Payload {
+        let fingerprint = Fingerprint::parse(&format!("rustc=1.80.0 (abc);target={target};profile=release;features=a,b%3Ac")).unwrap();
+        Payload::encode(encoding, compression, binary, SourceHash::new(CARGO, RUST), Some(fingerprint))
     }
 
-    if *config.base64() {
-        output_data.push(98);
+    fn rss_file(encoding: PayloadEncoding) -> RssFile {
+        let mut rss = RssFile::new(CARGO.to_string(), RUST.to_string());
+        rss.add_payload(payload(encoding, "x86_64-unknown-linux-gnu"));
+        rss
     }
-    else {
-        output_data.push(114);
+
+    fn assert_parses(contents: &[u8], expected: &RssFile) -> RssFile {
+        let parsed = RssFile::parse(contents).unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(parsed.payloads.len(), expected.payloads.len());
+        for (parsed, expected) in parsed.payloads.iter().zip(&expected.payloads) {
+            assert_eq!(parsed.binary(), expected.binary());
+            assert_eq!(parsed.encoding, expected.encoding);
+            assert_eq!(parsed.compression == Compression::None, expected.compression == Compression::None);
+            assert_eq!(parsed.hash, expected.hash);
+            assert_eq!(parsed.fingerprint, expected.fingerprint);
+        }
+        parsed
+    }
+
+    fn to_crlf(contents: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for b in contents {
+            if *b == b'\n' {
+                output.push(b'\r');
+            }
+            output.push(*b);
+        }
+        output
+    }
+
+    #[test]
+    fn round_trip() {
+        for encoding in [PayloadEncoding::Base64, PayloadEncoding::Raw] {
+            let rss = rss_file(encoding);
+            let parsed = assert_parses(&rss.serialize(), &rss);
+            assert_eq!(parsed.cargo_content, CARGO);
+            assert_eq!(parsed.rust_content, RUST);
+            assert!(parsed.is_up_to_date(&parsed.payloads[0]));
+        }
+    }
+
+    #[test]
+    fn multiple_targets() {
+        let mut rss = rss_file(PayloadEncoding::Base64);
+        rss.add_payload(payload(PayloadEncoding::Raw, "aarch64-unknown-linux-gnu"));
+        rss.add_payload(payload(PayloadEncoding::Base64, "x86_64-pc-windows-msvc"));
+        rss.add_payload(payload(PayloadEncoding::Raw, "x86_64-unknown-linux-gnu"));
+        assert_eq!(rss.payloads.len(), 3);
+
+        let parsed = assert_parses(&rss.serialize(), &rss);
+        assert_eq!(parsed.rust_content, RUST);
+        assert_eq!(parsed.payload("aarch64-unknown-linux-gnu").unwrap().encoding, PayloadEncoding::Raw);
+        assert_eq!(parsed.payload("x86_64-unknown-linux-gnu").unwrap().encoding, PayloadEncoding::Raw);
+        assert!(parsed.payload("riscv64gc-unknown-linux-gnu").is_none());
+    }
+
+    #[test]
+    fn multiple_targets_crlf() {
+        let mut rss = rss_file(PayloadEncoding::Base64);
+        rss.add_payload(payload(PayloadEncoding::Base64, "aarch64-unknown-linux-gnu"));
+        assert_parses(&to_crlf(&rss.serialize()), &rss);
+    }
+
+    #[test]
+    fn source_ending_in_comment() {
+        let mut rss = RssFile::new(CARGO.to_string(), format!("{RUST}\n/*:0b:0*/"));
+        rss.add_payload(payload(PayloadEncoding::Base64, "x86_64-unknown-linux-gnu"));
+        let parsed = RssFile::parse(&rss.serialize()).unwrap();
+        assert_eq!(parsed.rust_content, rss.rust_content);
+        assert_eq!(parsed.payloads.len(), 1);
+    }
+
+    #[test]
+    fn set_source_removes_stale_payloads() {
+        let mut rss = rss_file(PayloadEncoding::Base64);
+        assert_eq!(rss.set_source(CARGO.to_string(), RUST.to_string()), 0);
+        assert_eq!(rss.set_source(CARGO.to_string(), "fn main() {}".to_string()), 1);
+        assert!(rss.payloads.is_empty());
+    }
+
+    #[test]
+    fn compressed_payloads() {
+        for encoding in [PayloadEncoding::Base64, PayloadEncoding::Raw] {
+            let mut rss = RssFile::new(CARGO.to_string(), RUST.to_string());
+            rss.add_payload(payload_with(encoding, Compression::Deflate(9), [0; 10000].to_vec(), "x86_64-unknown-linux-gnu"));
+            rss.add_payload(payload(encoding, "aarch64-unknown-linux-gnu"));
+
+            let contents = rss.serialize();
+            assert!(contents.len() < 10000);
+            let parsed = assert_parses(&contents, &rss);
+            assert!(matches!(parsed.payloads[0].compression, Compression::Deflate(_)));
+            assert_eq!(parsed.payloads[1].compression, Compression::None);
+        }
+    }
+
+    #[test]
+    fn decodes_binaries_when_needed() {
+        for encoding in [PayloadEncoding::Base64, PayloadEncoding::Raw] {
+            let mut rss = RssFile::new(CARGO.to_string(), RUST.to_string());
+            rss.add_payload(payload_with(encoding, Compression::Deflate(6), (0..=255).cycle().take(100000).collect(), "x86_64-unknown-linux-gnu"));
+            let contents = rss.serialize();
+
+            let parsed = RssFile::parse_lazily(&contents).unwrap();
+            let payload = &parsed.payloads[0];
+            assert!(payload.binary.get().is_none());
+            assert_eq!(payload.cache_key(), rss.payloads[0].cache_key());
+            assert!(payload.check_executable_format().is_err());
+            assert!(payload.binary.get().is_none());
+            assert_eq!(payload.binary(), rss.payloads[0].binary());
+            assert_eq!(parsed.serialize(), contents);
+        }
+
+        let contents = String::from_utf8(rss_file(PayloadEncoding::Base64).serialize()).unwrap();
+        let corrupt = contents.replacen("AAEC", "A!EC", 1);
+        assert!(RssFile::parse(corrupt.as_bytes()).is_err());
+        assert!(RssFile::parse_lazily(corrupt.as_bytes()).unwrap().payloads[0].binary().is_err());
+    }
+
+    #[test]
+    fn keeps_legacy_payloads_for_other_targets() {
+        let legacy = |binary| Payload::encode(PayloadEncoding::Base64, Compression::None, binary, SourceHash::new(CARGO, RUST), None);
+        let mut elf = vec![0; 64];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[18] = 0x3E;
+
+        let mut rss = RssFile::new(CARGO.to_string(), RUST.to_string());
+        rss.payloads.push(legacy(elf));
+        rss.add_payload(payload(PayloadEncoding::Base64, "x86_64-unknown-linux-gnu"));
+        assert_eq!(rss.payloads.len(), 1);
+
+        // Built for Windows before targets were recorded
+        let mut rss = RssFile::parse_lazily(include_bytes!("../sample/sample.rss")).unwrap();
+        rss.add_payload(payload(PayloadEncoding::Base64, "x86_64-unknown-linux-gnu"));
+        assert_eq!(rss.payloads.len(), 2);
+        assert!(rss.payloads[0].executable_format().unwrap().is_for_target("x86_64-pc-windows-msvc"));
+        rss.add_payload(payload(PayloadEncoding::Base64, "x86_64-pc-windows-gnu"));
+        assert_eq!(rss.payloads.iter().filter_map(Payload::target).collect::<Vec<_>>(), ["x86_64-unknown-linux-gnu", "x86_64-pc-windows-gnu"]);
+    }
+
+    #[test]
+    fn finds_legacy_payloads_only_for_their_target() {
+        // Built for Windows before targets were recorded
+        let rss = RssFile::parse_lazily(include_bytes!("../sample/sample.rss")).unwrap();
+        assert!(rss.payload("x86_64-pc-windows-msvc").is_some());
+        assert!(rss.payload("aarch64-unknown-linux-gnu").is_none());
+        assert!(rss.payload("x86_64-unknown-linux-gnu").is_none());
+    }
+
+    #[test]
+    fn source_only() {
+        let fingerprint = payload(PayloadEncoding::Raw, "x86_64-unknown-linux-gnu").fingerprint.unwrap();
+        let mut rss = rss_file(PayloadEncoding::Base64);
+        rss.add_payload(payload(PayloadEncoding::Base64, "aarch64-unknown-linux-gnu"));
+        rss.set_source_only(Payload::source_only(CARGO, RUST, fingerprint.clone()));
+
+        let parsed = assert_parses(&rss.serialize(), &rss);
+        assert!(!parsed.has_binaries());
+        assert!(parsed.payload("x86_64-unknown-linux-gnu").unwrap().is_source_only());
+        assert_eq!(parsed.cache_key(&fingerprint), rss.cache_key(&fingerprint));
+        assert_ne!(parsed.cache_key(&fingerprint), parsed.cache_key(&fingerprint.with_target("aarch64-unknown-linux-gnu")));
+    }
+
+    #[test]
+    fn trailing_whitespace() {
+        for encoding in [PayloadEncoding::Base64, PayloadEncoding::Raw] {
+            let rss = rss_file(encoding);
+            for suffix in ["\n", "\n\n", "\r\n", " ", "\t", " \t\r\n \n"] {
+                let mut contents = rss.serialize();
+                contents.extend(suffix.as_bytes());
+                assert_parses(&contents, &rss);
+            }
+        }
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let rss = rss_file(PayloadEncoding::Base64);
+        let contents = to_crlf(&rss.serialize());
+
+        let parsed = assert_parses(&contents, &rss);
+        assert_eq!(parsed.cargo_content, CARGO.replace('\n', "\r\n"));
+        assert_eq!(parsed.rust_content, RUST.replace('\n', "\r\n"));
+        assert!(parsed.is_up_to_date(&parsed.payloads[0]));
+    }
+
+    #[test]
+    fn wrapped_base64_payload() {
+        let rss = rss_file(PayloadEncoding::Base64);
+        let b64 = STANDARD.encode(rss.payloads[0].binary().unwrap());
+        let wrapped = b64.as_bytes().chunks(76).map(|c| std::str::from_utf8(c).unwrap()).collect::<Vec<_>>().join("\r\n");
+
+        let contents = String::from_utf8(rss.serialize()).unwrap().replace(&b64, &format!("\r\n{wrapped}\r\n"));
+        assert_parses(contents.as_bytes(), &rss);
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let rss = rss_file(PayloadEncoding::Base64);
+        let contents = String::from_utf8(rss.serialize()).unwrap();
+        let b64 = STANDARD.encode(rss.payloads[0].binary().unwrap());
+        let contents = contents.replace(&b64, &b64[..100]);
+
+        assert_eq!(
+            RssFile::parse(contents.as_bytes()).err().unwrap().hint().unwrap(),
+            format!("payload length says {} bytes but only 100 are present", b64.len())
+        );
+    }
+
+    #[test]
+    fn renames_package() {
+        let cargo = "[package]\nname = \"old\"\nversion = \"0.1.0\"\n\n[[bin]]\nname = \"tool\"\n";
+        assert_eq!(package_name(cargo), Some("old"));
+        assert_eq!(rename_package(cargo, "new").unwrap(), cargo.replace("\"old\"", "\"new\""));
+
+        let cargo = "[dependencies]\nname = \"dep\"\n\n[package]\r\n  name='old' # comment\r\n";
+        assert_eq!(package_name(cargo), Some("old"));
+        assert_eq!(rename_package(cargo, "new").unwrap(), cargo.replace("'old'", "'new'"));
+
+        assert_eq!(package_name("[package]\nname.workspace = true\n"), None);
+        assert_eq!(package_name("[lib]\nname = \"lib\"\n"), None);
+    }
+
+    #[test]
+    fn renaming_drops_binaries() {
+        let mut rss = rss_file(PayloadEncoding::Base64);
+        rss.add_payload(payload(PayloadEncoding::Raw, "aarch64-unknown-linux-gnu"));
+        assert_eq!(rss.rename_package("renamed"), Some(true));
+        assert_eq!(package_name(rss.cargo_content()), Some("renamed"));
+        assert!(rss.payloads.is_empty());
+
+        let fingerprint = payload(PayloadEncoding::Raw, "x86_64-unknown-linux-gnu").fingerprint.unwrap();
+        let mut rss = RssFile::new(CARGO.to_string(), RUST.to_string());
+        rss.set_source_only(Payload::source_only(CARGO, RUST, fingerprint));
+        assert_eq!(rss.rename_package("renamed"), Some(false));
+        assert!(rss.payloads.is_empty());
+
+        let mut rss = RssFile::new("[package]\nname.workspace = true\n".to_string(), RUST.to_string());
+        rss.add_payload(payload(PayloadEncoding::Raw, "x86_64-unknown-linux-gnu"));
+        assert_eq!(rss.rename_package("renamed"), None);
+        assert_eq!(rss.payloads.len(), 1);
+    }
+
+    /// An empty directory for a test that writes files, with a config keeping backups if `backup` is set
+    fn write_directory(name: &str, backup: bool) -> (std::path::PathBuf, Config) {
+        let directory = std::env::temp_dir().join(format!("rss-write-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let options = ["true", "true", "cache", "nano", "none", "true", "cache", &backup.to_string(), "0", "release"];
+        let config = options.iter().enumerate().map(|(i, option)| format!("${i}={option}\n")).collect::<String>();
+        fs::write(Config::location(&directory), config).unwrap();
+        let config = Config::read(&directory).unwrap();
+        fs::remove_file(Config::location(&directory)).unwrap();
+        (directory, config)
+    }
+
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(directory).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect::<Vec<_>>();
+        names.sort();
+        names
     }
 
-    output_data.push(58);
-    let mut h = DefaultHasher::new();
-    cargo_content.hash(&mut h);
-    rust_content.hash(&mut h);
-    #[cfg(target_os = "windows")]
-    "windows".hash(&mut h);
     #[cfg(target_os = "linux")]
-    "linux".hash(&mut h);
+    #[test]
+    fn rewriting_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
 
-    output_data.extend(format!("{}", h.finish()).as_bytes());
+        let (directory, config) = write_directory("permissions", false);
+        let rss_file = directory.join("script.rss");
+        fs::write(&rss_file, "old").unwrap();
+        fs::set_permissions(&rss_file, fs::Permissions::from_mode(0o750)).unwrap();
+        write_rss_file(&config, &rss_file, b"new").unwrap();
+        assert_eq!(fs::read(&rss_file).unwrap(), b"new");
+        assert_eq!(fs::metadata(&rss_file).unwrap().permissions().mode() & 0o777, 0o750);
+        fs::remove_dir_all(directory).unwrap();
+    }
 
-    output_data.extend("*/".to_string().as_bytes());
+    #[test]
+    fn rewriting_backs_up_previous_version() {
+        let (directory, config) = write_directory("backup", true);
+        let rss_file = directory.join("script.rss");
+        fs::write(&rss_file, "first").unwrap();
+        write_rss_file(&config, &rss_file, b"second").unwrap();
+        write_rss_file(&config, &rss_file, b"third").unwrap();
+        assert_eq!(fs::read(&rss_file).unwrap(), b"third");
+        assert_eq!(fs::read(directory.join("script.rss.bak")).unwrap(), b"second");
+        assert_eq!(file_names(&directory), ["script.rss", "script.rss.bak"]);
+        fs::remove_dir_all(directory).unwrap();
+    }
 
-    fs::write(&rss_file, &output_data).map_err(|_| format!("Failed write to [{}]", rss_file.display()))?;
+    #[test]
+    fn failed_rewrite_leaves_no_temporary_file() {
+        let (directory, config) = write_directory("failed", true);
+        let rss_file = directory.join("script.rss");
+        fs::write(&rss_file, "old").unwrap();
+        // The backup can't replace a directory, so the rewrite fails after the new contents are written
+        fs::create_dir_all(directory.join("script.rss.bak").join("taken")).unwrap();
+        assert!(write_rss_file(&config, &rss_file, b"new").is_err());
+        assert_eq!(fs::read(&rss_file).unwrap(), b"old");
+        assert_eq!(file_names(&directory), ["script.rss", "script.rss.bak"]);
+        fs::remove_dir_all(directory).unwrap();
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn reads_rss_metadata() {
+        let cargo = "[package]\nname = \"test\"\n\n[package.metadata.rss]\nprofile = \"dev\"\n";
+        assert_eq!(rss_metadata(cargo).get("profile").and_then(|p| p.as_str()), Some("dev"));
+        assert!(rss_metadata(CARGO).is_empty());
+        assert!(rss_metadata("[package\n").is_empty());
+    }
+
+    #[test]
+    fn adds_rss_metadata() {
+        let cargo = "[package]\nname = \"test\"\n\n[package.metadata.rss]\nprofile = \"dev\"\n";
+        let added = add_rss_metadata(cargo, "bin", "tool").unwrap();
+        assert_eq!(added, "[package]\nname = \"test\"\n\n[package.metadata.rss]\nbin = \"tool\"\nprofile = \"dev\"\n");
+        assert_eq!(rss_metadata(&added).get("profile").and_then(|p| p.as_str()), Some("dev"));
+
+        let added = add_rss_metadata("[package]\nname = \"test\"", "bin", "tool").unwrap();
+        assert_eq!(rss_metadata(&added).get("bin").and_then(|b| b.as_str()), Some("tool"));
+
+        assert_eq!(add_rss_metadata("[package]\nname = \"test\"\nmetadata.rss = { profile = \"dev\" }\n", "bin", "tool"), None);
+    }
+
+    #[test]
+    fn short_inputs_do_not_panic() {
+        for contents in [&b""[..], b"\n", b"\r\n", b" ", b"*/", b":*/", b"b:0*/", b"r:0*/", b":b:0*/", b"/*:0b:0*/", b"/*#:0b:0*/", b"/**/\n\n/*:0b:0*/"] {
+            let _ = RssFile::parse(contents);
+        }
+    }
+
+    #[test]
+    fn legacy_hash() {
+        let mut rss = rss_file(PayloadEncoding::Base64);
+        rss.payloads[0].hash = SourceHash::legacy(CARGO, RUST);
+        rss.payloads[0].fingerprint = None;
+        let contents = rss.serialize();
+        assert!(!String::from_utf8_lossy(&contents).contains(SHA256_PREFIX));
+
+        let mut parsed = assert_parses(&contents, &rss);
+        assert!(parsed.payloads[0].hash.is_legacy());
+        assert!(parsed.is_up_to_date(&parsed.payloads[0]));
+        assert!(parsed.payload("x86_64-unknown-linux-gnu").is_some());
+
+        assert!(parsed.upgrade_hashes());
+        let upgraded = RssFile::parse(&parsed.serialize()).unwrap();
+        assert_eq!(upgraded.payloads[0].hash, SourceHash::new(CARGO, RUST));
+        assert!(upgraded.is_up_to_date(&upgraded.payloads[0]));
+    }
+
+    #[test]
+    fn sha256_hash() {
+        let hash = SourceHash::new(CARGO, RUST);
+        let text = hash.to_string();
+        assert_eq!(text.len(), SHA256_PREFIX.len() + 64);
+        assert_eq!(SourceHash::parse(text.as_bytes()), Some(hash));
+
+        assert!(hash.matches(&CARGO.replace('\n', "\r\n"), RUST));
+        assert!(!hash.matches(CARGO, "fn main() {}"));
+        assert_ne!(SourceHash::new("ab", "c"), SourceHash::new("a", "bc"));
+        assert_eq!(SourceHash::parse(b"sha256-00"), None);
+    }
+
+    #[test]
+    fn fingerprint_escaping() {
+        let fingerprint = payload(PayloadEncoding::Raw, "x86_64-unknown-linux-gnu").fingerprint.unwrap();
+        assert_eq!(fingerprint.features(), &["a", "b:c"]);
+        assert_eq!(fingerprint.to_string(), "rustc=1.80.0 (abc);target=x86_64-unknown-linux-gnu;profile=release;features=a,b%3Ac");
+    }
+
+    #[test]
+    fn empty_source() {
+        let contents = b"/**/\n\n/*:0b:0*/";
+        let parsed = RssFile::parse(contents).unwrap();
+        assert_eq!(parsed.cargo_content, "");
+        assert_eq!(parsed.rust_content, "");
+        assert!(parsed.payloads[0].is_source_only());
+    }
+}
@@ -0,0 +1,163 @@
+use std::io;
+use std::process::{Child, Command, ExitStatus};
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+/// Signals forwarded to the running child
+#[cfg(target_os = "linux")]
+const FORWARDED_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// The running children, each as its process id, or its negated process group id if signals go to its whole group.
+/// Free slots are 0 and slots claimed for a child that is being started are `RESERVED`. Several builds can run at once
+/// with `rss build -j`.
+#[cfg(target_os = "linux")]
+static CHILDREN: [AtomicI32; 64] = [const { AtomicI32::new(0) }; 64];
+
+#[cfg(target_os = "linux")]
+const RESERVED: i32 = i32::MIN;
+
+/// Whether `forward_signals` installed the handlers
+#[cfg(target_os = "linux")]
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The first forwarded signal received, or 0
+#[cfg(target_os = "linux")]
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+/// How a child receives the signals sent to rss
+#[derive(Clone, Copy)]
+pub enum Forward {
+    /// The child shares rss's process group, so signals from the terminal already reach it and only signals sent to
+    /// rss itself are forwarded
+    Process,
+    /// The child is started in its own process group, which is sent every signal rss receives
+    ProcessGroup
+}
+
+#[cfg(target_os = "linux")]
+extern "C" fn forward_signal(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    let _ = RECEIVED.compare_exchange(0, signal, Ordering::SeqCst, Ordering::SeqCst);
+
+    // The terminal signals the whole foreground process group with `SI_KERNEL`
+    let from_terminal = unsafe { (*info).si_code } == libc::SI_KERNEL;
+    for child in &CHILDREN {
+        let child = child.load(Ordering::SeqCst);
+        if (child < 0 && child != RESERVED) || (child > 0 && !from_terminal) {
+            unsafe { libc::kill(child, signal); }
+        }
+    }
+}
+
+/// Catches SIGINT, SIGTERM and SIGHUP so they are forwarded to the running child rather than ending rss before it
+/// has cleaned up. Signals that were ignored when rss started stay ignored.
+pub fn forward_signals() {
+    #[cfg(target_os = "linux")]
+    for signal in FORWARDED_SIGNALS {
+        unsafe {
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(signal, std::ptr::null(), &mut previous);
+            if previous.sa_sigaction == libc::SIG_IGN {
+                continue;
+            }
+
+            // No SA_RESTART so that waiting for a lock is interrupted
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = forward_signal as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+            INSTALLED.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Whether a forwarded signal has been received
+pub fn is_interrupted() -> bool {
+    #[cfg(target_os = "linux")]
+    return RECEIVED.load(Ordering::SeqCst) != 0;
+    #[cfg(target_os = "windows")]
+    return false;
+}
+
+/// Runs `command` to completion, forwarding signals to it as described by `forward`, and returns its exit status
+pub fn run_forwarding_signals(command: &mut Command, forward: Forward) -> io::Result<ExitStatus> {
+    run_forwarding_signals_with(command, forward, |_| {})
+}
+
+/// Like [`run_forwarding_signals`], but calls `output` with the started child before waiting for it, so that its
+/// piped output can be read
+pub fn run_forwarding_signals_with(command: &mut Command, forward: Forward, output: impl FnOnce(&mut Child)) -> io::Result<ExitStatus> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::process::CommandExt;
+
+        let slot = CHILDREN.iter().find(|c| c.compare_exchange(0, RESERVED, Ordering::SeqCst, Ordering::SeqCst).is_ok());
+        // A child in its own group never sees the terminal's signals, so it must only be moved out of rss's group if
+        // they can be forwarded to it
+        let forward = match forward {
+            Forward::ProcessGroup if slot.is_some() && INSTALLED.load(Ordering::SeqCst) => Forward::ProcessGroup,
+            _ => Forward::Process
+        };
+        if let Forward::ProcessGroup = forward {
+            command.process_group(0);
+        }
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                if let Some(slot) = slot {
+                    slot.store(0, Ordering::SeqCst);
+                }
+                return Err(e);
+            }
+        };
+        let pid = child.id() as i32;
+        let target = match forward {
+            Forward::Process => pid,
+            Forward::ProcessGroup => -pid
+        };
+        if let Some(slot) = slot {
+            slot.store(target, Ordering::SeqCst);
+        }
+
+        // A signal received just before the child was recorded would otherwise be lost
+        let received = RECEIVED.load(Ordering::SeqCst);
+        if received != 0 {
+            unsafe { libc::kill(target, received); }
+        }
+
+        output(&mut child);
+        let status = child.wait();
+        if let Some(slot) = slot {
+            slot.store(0, Ordering::SeqCst);
+        }
+        status
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let _ = forward;
+        let mut child = command.spawn()?;
+        output(&mut child);
+        child.wait()
+    }
+}
+
+/// Exits by the forwarded signal rss received, if any. Called once temporaries have been cleaned up.
+pub fn exit_if_interrupted() {
+    #[cfg(target_os = "linux")]
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => {}
+        signal => exit_with_signal(signal)
+    }
+}
+
+/// Ends this process with `signal`, so that the parent sees the same status as if rss had been killed by it
+#[cfg(target_os = "linux")]
+pub fn exit_with_signal(signal: libc::c_int) -> ! {
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+    // Signals such as SIGCHLD are ignored by default so fall back to the shell convention
+    std::process::exit(128 + signal)
+}
@@ -0,0 +1,43 @@
+//! Checks which scripts `rss build` rebuilds, and that `--check` only reports them
+
+use std::fs;
+
+mod common;
+
+use common::Script;
+
+const RUST: &str = "fn main() {\n    println!(\"hello\");\n}";
+
+#[test]
+fn check_reports_stale_scripts_without_building() {
+    let script = Script::new("build-check", "greeter", RUST);
+    let contents = fs::read(script.file()).unwrap();
+
+    let output = script.rss(&["build", "--check"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Stale"));
+    assert_eq!(fs::read(script.file()).unwrap(), contents);
+    script.assert_clean();
+
+    script.build();
+    let output = script.rss(&["build", "--check"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("up to date"));
+}
+
+#[test]
+fn force_rebuilds_up_to_date_scripts() {
+    let script = Script::new("build-force", "greeter", RUST);
+    script.build();
+
+    let output = script.rss(&["build"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Up to date"));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Building project"));
+
+    let output = script.rss(&["build", "--force"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Up to date"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Building project"));
+    script.assert_clean();
+}
@@ -0,0 +1,81 @@
+//! A script in a directory of its own, with its own cache, shared by the tests that run rss
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// A directory holding the script and the cache used by rss, removed when dropped
+pub struct Script {
+    pub directory: PathBuf,
+    name: String
+}
+
+impl Script {
+    /// A script named `name` with the source `rust` and a stale binary-less payload, so that it is built into the user
+    /// cache when run. `test` keeps the directories of tests running at the same time apart.
+    pub fn new(test: &str, name: &str, rust: &str) -> Script {
+        let directory = env::temp_dir().join(format!("rss-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("scripts")).unwrap();
+        let cargo = format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n");
+        let script = Script { directory, name: name.to_string() };
+        fs::write(script.file(), format!("/*{cargo}*/\n{rust}\n/*:0b:0*/")).unwrap();
+        script
+    }
+
+    pub fn file(&self) -> PathBuf {
+        self.directory.join("scripts").join(format!("{}.rss", self.name))
+    }
+
+    pub fn cache(&self) -> PathBuf {
+        self.directory.join("cache")
+    }
+
+    /// rss with `args` followed by the script
+    pub fn rss(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_rss"));
+        command.args(args).arg(self.file()).env("XDG_CACHE_HOME", self.cache()).stdin(Stdio::null());
+        command
+    }
+
+    /// Runs rss with `args` followed by the script and `script_args`, leaving stderr out of the output
+    pub fn run(&self, args: &[&str], script_args: &[&str]) -> Output {
+        self.rss(args).args(script_args).stderr(Stdio::null()).output().unwrap()
+    }
+
+    pub fn build(&self) {
+        let status = self.rss(&["build"]).stdout(Stdio::null()).stderr(Stdio::null()).status().unwrap();
+        assert!(status.success());
+    }
+
+    /// Asserts that only the script remains next to it, and no partial binaries or workspaces remain in the cache
+    pub fn assert_clean(&self) {
+        let entries = fs::read_dir(self.directory.join("scripts")).unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [self.file().file_name().unwrap()]);
+
+        if let Ok(entries) = fs::read_dir(self.cache().join("rss")) {
+            for entry in entries {
+                let name = entry.unwrap().file_name();
+                assert!(!name.to_string_lossy().ends_with(".tmp"), "{name:?} left in the cache");
+            }
+        }
+        assert_eq!(self.workspaces().len(), 0, "workspace left behind");
+    }
+
+    /// Workspaces that projects are being generated and built in
+    pub fn workspaces(&self) -> Vec<PathBuf> {
+        fs::read_dir(self.cache().join("rss").join("workspaces"))
+            .map(|entries| entries.map(|e| e.unwrap().path()).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for Script {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
@@ -0,0 +1,53 @@
+//! Starts several runs and builds of the same script at once and checks that it is only built once
+
+use std::fs;
+use std::process::{Child, Stdio};
+
+mod common;
+
+use common::Script;
+
+const RUST: &str = "fn main() {\n    println!(\"hello\");\n}";
+
+/// Starts rss with `args` on the script, capturing its output
+fn spawn(script: &Script, args: &[&str]) -> Child {
+    script.rss(args).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap()
+}
+
+/// Waits for each of `children`, which must succeed, and counts those that printed `message`
+fn count_printed(children: Vec<Child>, message: &str) -> usize {
+    children.into_iter()
+        .map(|child| child.wait_with_output().unwrap())
+        .inspect(|output| assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr)))
+        .filter(|output| String::from_utf8_lossy(&output.stdout).contains(message) || String::from_utf8_lossy(&output.stderr).contains(message))
+        .count()
+}
+
+#[test]
+fn builds_into_the_cache_once() {
+    let script = Script::new("locking-cache", "greeter", RUST);
+    let runs = (0..4).map(|_| spawn(&script, &["run"])).collect();
+    assert_eq!(count_printed(runs, "Building into the user cache"), 1);
+    script.assert_clean();
+}
+
+#[test]
+fn rebuilds_once() {
+    let script = Script::new("locking-rebuild", "greeter", RUST);
+    script.build();
+    let contents = fs::read(script.file()).unwrap();
+    let contents = String::from_utf8_lossy(&contents).replacen("\"hello\"", "\"hello again\"", 1);
+    fs::write(script.file(), contents.as_bytes()).unwrap();
+
+    let runs = (0..4).map(|_| spawn(&script, &["run"])).collect();
+    assert_eq!(count_printed(runs, "Rebuilding project"), 1);
+    script.assert_clean();
+}
+
+#[test]
+fn builds_once() {
+    let script = Script::new("locking-build", "greeter", RUST);
+    let builds = (0..4).map(|_| spawn(&script, &["build"])).collect();
+    assert_eq!(count_printed(builds, "Building project"), 1);
+    script.assert_clean();
+}
@@ -0,0 +1,79 @@
+//! Checks that `rss run` leaves stdout, arguments and the exit status to the script, however the script is built first
+
+use std::fs;
+
+mod common;
+
+use common::Script;
+
+const GREETER: &str = "fn main() {\n    println!(\"hello\");\n}";
+
+/// Prints its arguments, then exits with the code given after `exit`, or aborts if told to
+const ECHO: &str = r#"fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    println!("{args:?}");
+    match args.first().map(String::as_str) {
+        Some("exit") => std::process::exit(args[1].parse().unwrap()),
+        Some("abort") => std::process::abort(),
+        _ => {}
+    }
+}"#;
+
+/// Runs the script, returning what it printed to stdout
+fn stdout(script: &Script, script_args: &[&str]) -> String {
+    let output = script.run(&["run"], script_args);
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn building_into_the_cache_keeps_stdout_clean() {
+    let script = Script::new("output-cache", "greeter", GREETER);
+    assert_eq!(stdout(&script, &[]), "hello\n");
+    assert_eq!(stdout(&script, &[]), "hello\n");
+}
+
+#[test]
+fn rebuilding_keeps_stdout_clean() {
+    let script = Script::new("output-rebuild", "greeter", GREETER);
+    script.build();
+
+    // The embedded binary no longer matches the source
+    let contents = fs::read(script.file()).unwrap();
+    let contents = String::from_utf8_lossy(&contents).replacen("\"hello\"", "\"hello again\"", 1);
+    fs::write(script.file(), contents.as_bytes()).unwrap();
+    assert_eq!(stdout(&script, &[]), "hello again\n");
+}
+
+#[test]
+fn forwards_arguments() {
+    let script = Script::new("output-arguments", "echo", ECHO);
+    assert_eq!(stdout(&script, &["a b", "--flag", "-x"]), "[\"a b\", \"--flag\", \"-x\"]\n");
+    // A single leading separator belongs to rss, any later ones to the script
+    assert_eq!(stdout(&script, &["--", "--", "a"]), "[\"--\", \"a\"]\n");
+
+    // Without the run command, everything after the script goes to it
+    let output = script.rss(&[]).arg("build").output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "[\"build\"]\n");
+}
+
+#[test]
+fn exits_with_the_script_status() {
+    let script = Script::new("output-status", "echo", ECHO);
+    for code in [0, 1, 3, 42] {
+        let output = script.run(&["run"], &["exit", &code.to_string()]);
+        assert_eq!(output.status.code(), Some(code));
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn exits_by_the_signal_that_ended_the_script() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Stdio;
+
+    let script = Script::new("output-signal", "echo", ECHO);
+    let status = script.rss(&["run"]).arg("abort").stdout(Stdio::null()).stderr(Stdio::null()).status().unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGABRT));
+}
@@ -0,0 +1,136 @@
+//! Kills `rss run` at each stage and checks that signals reach the script and nothing is left behind
+#![cfg(target_os = "linux")]
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+use common::Script;
+
+const RUST: &str = r#"use std::io::Write;
+
+fn main() {
+    println!("pid {}", std::process::id());
+    std::io::stdout().flush().unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(60));
+}
+"#;
+
+fn sleeper(test: &str) -> Script {
+    Script::new(&format!("signals-{test}"), "sleeper", RUST)
+}
+
+/// Starts running the script, reading its output through a pipe
+fn run(script: &Script) -> Child {
+    script.rss(&["run"]).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().unwrap()
+}
+
+fn kill(pid: u32, signal: i32) {
+    assert_eq!(unsafe { libc::kill(pid as i32, signal) }, 0);
+}
+
+fn is_running(pid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+
+/// Processes, such as cargo and rustc, whose command line mentions `directory`
+fn processes_using(directory: &Path) -> Vec<String> {
+    let directory = directory.to_string_lossy().to_string();
+    fs::read_dir("/proc").unwrap()
+        .filter_map(|e| fs::read(e.ok()?.path().join("cmdline")).ok())
+        .map(|c| String::from_utf8_lossy(&c).replace('\0', " "))
+        .filter(|c| c.contains(&directory))
+        .collect()
+}
+
+/// Waits until a project has been generated in a workspace
+fn wait_for_project(script: &Script) {
+    let start = Instant::now();
+    while !script.workspaces().iter().any(|w| w.join("Cargo.toml").exists()) {
+        assert!(start.elapsed() < Duration::from_secs(60), "project was never generated");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Reads the output of rss until the script prints its process id
+fn script_pid(rss: &mut Child) -> u32 {
+    let stdout = BufReader::new(rss.stdout.take().unwrap());
+    for line in stdout.lines() {
+        if let Some(pid) = line.unwrap().strip_prefix("pid ") {
+            return pid.parse().unwrap();
+        }
+    }
+    panic!("script did not start");
+}
+
+/// Kills rss while the script runs and checks that the script and rss both end by the signal
+fn assert_forwarded(script: &Script, signal: i32) {
+    let mut rss = run(script);
+    let pid = script_pid(&mut rss);
+    assert!(is_running(pid));
+
+    kill(rss.id(), signal);
+    let status = rss.wait().unwrap();
+    assert_eq!(status.signal(), Some(signal));
+    assert!(!is_running(pid), "script still running after rss exited");
+    script.assert_clean();
+}
+
+#[test]
+fn forwards_signals_to_embedded_binary() {
+    let script = sleeper("embedded");
+    script.build();
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        assert_forwarded(&script, signal);
+    }
+}
+
+#[test]
+fn forwards_signals_to_cached_binary() {
+    let script = sleeper("cached");
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        assert_forwarded(&script, signal);
+    }
+}
+
+#[test]
+fn cleans_up_when_killed_while_building() {
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        let script = sleeper(&format!("building-{signal}"));
+        let rss = run(&script);
+        wait_for_project(&script);
+
+        kill(rss.id(), signal);
+        let status = rss.wait_with_output().unwrap().status;
+        assert_eq!(status.signal(), Some(signal));
+        script.assert_clean();
+    }
+}
+
+#[test]
+fn cleans_up_when_build_is_killed_from_terminal() {
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        let script = sleeper(&format!("build-{signal}"));
+        // In its own process group, which is signalled as a whole like the terminal's foreground group
+        let rss = script.rss(&["build"]).stdout(Stdio::null()).stderr(Stdio::null()).process_group(0).spawn().unwrap();
+        wait_for_project(&script);
+
+        assert_eq!(unsafe { libc::kill(-(rss.id() as i32), signal) }, 0);
+        let status = rss.wait_with_output().unwrap().status;
+        assert_eq!(status.signal(), Some(signal));
+
+        // Cargo runs in its own process group, so it only ends if rss forwarded the signal
+        let start = Instant::now();
+        while !processes_using(&script.directory).is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10), "still running: {:?}", processes_using(&script.directory));
+            thread::sleep(Duration::from_millis(10));
+        }
+        script.assert_clean();
+    }
+}